@@ -3,7 +3,10 @@ use std::fs::create_dir_all;
 
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
-use arbitrage_wallet::msg::{InstantiateMsg, ExecuteMsg, QueryMsg, StateResponse};
+use arbitrage_wallet::msg::{
+    CodeIdWhitelistResponse, ExecuteMsg, FactoryWhitelistResponse, FundersResponse, InstantiateMsg,
+    ManagementFeeResponse, MigrateMsg, MigrationProposalResponse, QueryMsg, StateResponse, WhitelistResponse,
+};
 use arbitrage_wallet::state::State;
 
 fn main() {
@@ -14,7 +17,14 @@ fn main() {
 
     export_schema(&schema_for!(InstantiateMsg), &out_dir);
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
+    export_schema(&schema_for!(MigrateMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
     export_schema(&schema_for!(State), &out_dir);
     export_schema(&schema_for!(StateResponse), &out_dir);
+    export_schema(&schema_for!(ManagementFeeResponse), &out_dir);
+    export_schema(&schema_for!(MigrationProposalResponse), &out_dir);
+    export_schema(&schema_for!(FundersResponse), &out_dir);
+    export_schema(&schema_for!(WhitelistResponse), &out_dir);
+    export_schema(&schema_for!(CodeIdWhitelistResponse), &out_dir);
+    export_schema(&schema_for!(FactoryWhitelistResponse), &out_dir);
 }
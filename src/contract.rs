@@ -1,23 +1,48 @@
+use std::convert::TryInto;
+
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
-use cosmwasm_std::{to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Addr, Uint128, Decimal, BankMsg, CosmosMsg, Coin, WasmMsg};
-use cw2::set_contract_version;
-use cw20::Cw20ExecuteMsg;
+use cosmwasm_std::{to_binary, from_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Addr, Uint128, Decimal, BankMsg, CosmosMsg, Coin, WasmMsg, SubMsg, Reply, ContractResult, Order, StdError, Event, Attribute};
+use cw2::{get_contract_version, set_contract_version};
+use cw20::{Cw20ExecuteMsg, Cw20ReceiveMsg, MinterResponse};
+use cw_storage_plus::{Bound, U64Key};
+use terra_cosmwasm::TerraQuerier;
 
 use crate::error::ContractError;
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, StateResponse};
-use crate::state::{State, STATE, Asset};
-use crate::MINIMUM_COMMISSION;
-use crate::util::{query_token_balance, add_cw20_msg};
+use crate::migrations::{self, parse_version};
+use crate::payload::{check_recipients, check_variant};
+use crate::msg::{CodeIdRuleResponse, CodeIdWhitelistResponse, ExecuteMsg, FactoryRuleResponse, FactoryWhitelistResponse, FunderResponse, FundersResponse, InstantiateMsg, ManagementFeeResponse, MigrateMsg, MigrationProposalResponse, QueryMsg, ReceiveMsg, StateResponse, TokenInstantiateMsg, WhitelistEntryResponse, WhitelistResponse};
+use crate::state::{State, STATE, Asset, AssetAmount, CODE_ID_WHITELIST, CommissionTier, FACTORY_WHITELIST, FUNDERS, MigrationProposal, MIGRATION_PROPOSAL, TradeGuard, TradeSnapshot, TRADE_SNAPSHOT, WhitelistEntry, WhitelistRule, WHITELIST};
+use crate::MIGRATION_PROPOSAL_BLOCKS;
+use crate::util::{query_token_balance, query_asset_balances, add_asset_msgs};
+use crate::split::{split_assets, WithdrawSplit};
+use crate::whitelist::{allowed_msgs, pin_whitelist, query_code_id};
+use crate::valuation::{AssetPrice, PriceSource, asset_price, query_asset_values, query_price, value_balances, total_value, validate_price_sources};
 
 // version info for migration info
-const CONTRACT_NAME: &str = "crates.io:counter";
+const CONTRACT_NAME: &str = "crates.io:arbitrage_wallet";
+// name stored by instances from before 0.2.0
+const LEGACY_CONTRACT_NAME: &str = "crates.io:counter";
+// first version that requires an approved migration proposal
+const PROPOSALS_VERSION: &str = "0.3.0";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// reply id for trades forwarded by send_native/send_cw20
+pub const TRADE_REPLY_ID: u64 = 1;
+// reply id for instantiating the share token
+pub const SHARE_TOKEN_REPLY_ID: u64 = 2;
+
+// cw20 actions that credit tokens to the `to`/`recipient` address
+const CW20_RECEIVE_ACTIONS: [&str; 5] = ["transfer", "send", "mint", "transfer_from", "send_from"];
+
+// page sizes for list queries
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
@@ -25,32 +50,91 @@ pub fn instantiate(
     // Save who the owner & user are
     let mut state = State::new(
         info.clone().sender,
-        msg.clone().funder
+        msg.clone().funder,
+        msg.clone().base_asset,
+        env.block.time.seconds(),
     );
 
     // Assign state items that were specified
     if let Some(whitelist) = msg.whitelist {
-        state.whitelist = whitelist;
+        for address in whitelist {
+            let entry = WhitelistEntry {
+                allowed_msgs: None,
+                code_id: query_code_id(&deps.querier, &address)?,
+            };
+            WHITELIST.save(deps.storage, &address, &entry)?;
+        }
     }
     if let Some(assets) = msg.assets {
         state.assets = assets;
+        // Deposits are made in the base asset, so it must always be held
+        if !state.assets.contains(&state.base_asset) {
+            state.assets.insert(0, state.base_asset.clone());
+        }
+    }
+    if let Some(min_commission) = msg.min_commission {
+        state.min_commission = min_commission;
+        state.commission = min_commission;
+    }
+    if let Some(max_commission) = msg.max_commission {
+        state.max_commission = max_commission;
+    }
+    if state.min_commission > state.max_commission || state.max_commission > Decimal::one() {
+        return Err(ContractError::InvalidCommissionBounds {})
     }
     if let Some(commission) = msg.commission {
-        if commission >= MINIMUM_COMMISSION {
-            state.commission = commission;
-        }
+        state.commission = commission;
+    }
+    if let Some(commission_tiers) = msg.commission_tiers {
+        state.commission_tiers = commission_tiers;
     }
+    validate_commission(&state)?;
     if let Some(addr) = msg.trader_withdrawal_address {
         state.trader_withdrawal_address = addr;
     }
+    if let Some(price_sources) = msg.price_sources {
+        state.price_sources = price_sources;
+    }
+    validate_price_sources(&state)?;
+    if let Some(hurdle_rate) = msg.hurdle_rate {
+        state.hurdle_rate = Some(hurdle_rate);
+    }
+    if let Some(management_fee) = msg.management_fee {
+        state.management_fee = Some(management_fee);
+    }
+    if let Some(pooled) = msg.pooled {
+        state.pooled = pooled;
+    }
+
+    let mut res = Response::new()
+        .add_attribute("method", "instantiate")
+        .add_attribute("owner", info.sender)
+        .add_attribute("user", msg.funder.to_string());
+
+    // Shares held as tokens are always pooled, the wallet mints them and
+    // is told the token's address in the reply
+    if let Some(token) = msg.share_token {
+        state.pooled = true;
+        let token_msg = TokenInstantiateMsg {
+            name: token.name.clone(),
+            symbol: token.symbol,
+            decimals: 6,
+            initial_balances: vec![],
+            mint: Some(MinterResponse { minter: env.contract.address.to_string(), cap: None }),
+        };
+        res = res.add_submessage(SubMsg::reply_on_success(WasmMsg::Instantiate {
+            admin: None,
+            code_id: token.code_id,
+            msg: to_binary(&token_msg)?,
+            funds: vec![],
+            label: token.name,
+        }, SHARE_TOKEN_REPLY_ID));
+    }
 
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
     STATE.save(deps.storage, &state)?;
 
-    Ok(Response::new()
-        .add_attribute("method", "instantiate")
-        .add_attribute("owner", info.sender)
-        .add_attribute("user", msg.funder.to_string()))
+    Ok(res)
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
@@ -62,52 +146,79 @@ pub fn execute(
 ) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::ToggleLock {} => toggle_lock(deps, info),
-        ExecuteMsg::Deposit {} => deposit(deps, info),
+        ExecuteMsg::Deposit {} => deposit(deps, env, info),
         ExecuteMsg::Withdraw {
-            amount
+            amount,
+            split,
+            preference,
+            min_out,
             } => withdraw(
                 deps,   
                 info,   
                 env,    
-                amount
+                amount,
+                split,
+                preference,
+                min_out,
             ),
         ExecuteMsg::UpdateState {
-            whitelist,
             assets,
             commission,
+            commission_tiers,
             user,
+            price_sources,
+            hurdle_rate,
+            management_fee,
             } => update_state(
                 deps,
                 info,
                 env,
-                whitelist,
                 assets,
                 commission,
+                commission_tiers,
                 user,
+                price_sources,
+                hurdle_rate,
+                management_fee,
             ),
+        ExecuteMsg::ReplacePriceSource { asset, source } => replace_price_source(deps, info, asset, source),
+        ExecuteMsg::AddWhitelist { address, allowed_msgs } => add_whitelist(deps, info, address, allowed_msgs),
+        ExecuteMsg::RemoveWhitelist { address } => remove_whitelist(deps, info, address),
+        ExecuteMsg::AddCodeIdWhitelist { code_id, allowed_msgs } => add_code_id_whitelist(deps, info, code_id, allowed_msgs),
+        ExecuteMsg::RemoveCodeIdWhitelist { code_id } => remove_code_id_whitelist(deps, info, code_id),
+        ExecuteMsg::AddFactoryWhitelist { factory, allowed_msgs } => add_factory_whitelist(deps, info, factory, allowed_msgs),
+        ExecuteMsg::RemoveFactoryWhitelist { factory } => remove_factory_whitelist(deps, info, factory),
         ExecuteMsg::SendNative {
             address,
             funds,
             msg,
+            min_nav_after,
+            max_loss_bps,
             } => send_native(
                 deps,
+                env,
                 info,
                 address,
                 funds,
                 msg,
+                TradeGuard { min_nav_after, max_loss_bps },
             ),
         ExecuteMsg::SendCw20 {
             address,
             token_addr,
             amount,
-            msg
+            msg,
+            min_nav_after,
+            max_loss_bps,
             } => send_cw20(
                 deps,
+                env,
                 info,
                 address,
                 token_addr,
                 amount,
-                msg
+                msg,
+                TradeGuard { min_nav_after, max_loss_bps },
             ),
         ExecuteMsg::UpdateWithdrawal {
             address,
@@ -116,7 +227,122 @@ pub fn execute(
                 info,
                 address,
             ),
+        ExecuteMsg::Receive(msg) => receive_cw20(deps, env, info, msg),
+        ExecuteMsg::ClaimManagementFee {} => claim_management_fee(deps, env, info),
+        ExecuteMsg::ClaimCommission {} => claim_commission(deps, env, info),
+        ExecuteMsg::Redeem { shares, split } => redeem(deps, env, info, shares, split),
+        ExecuteMsg::ProposeMigration {
+            code_id,
+            } => propose_migration(
+                deps,
+                info,
+                env,
+                code_id,
+            ),
+        ExecuteMsg::ApproveMigration {
+            code_id,
+            } => approve_migration(
+                deps,
+                info,
+                env,
+                code_id,
+            ),
+        ExecuteMsg::ExecuteMigration {} => execute_migration(deps, info, env),
+    }
+}
+
+fn propose_migration(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    code_id: u64,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+
+    if info.sender != state.trader {
+        return Err(ContractError::Unauthorized {})
+    }
+
+    // A new proposal replaces any earlier one, approved or not
+    let proposal = MigrationProposal {
+        code_id,
+        expires_at: env.block.height + MIGRATION_PROPOSAL_BLOCKS,
+        approved: false,
+    };
+    MIGRATION_PROPOSAL.save(deps.storage, &proposal)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "propose_migration")
+        .add_attribute("code_id", code_id.to_string())
+        .add_attribute("expires_at", proposal.expires_at.to_string()))
+}
+
+fn approve_migration(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    code_id: u64,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+
+    if info.sender != state.funder {
+        return Err(ContractError::Unauthorized {})
+    }
+
+    let mut proposal = match MIGRATION_PROPOSAL.may_load(deps.storage)? {
+        Some(proposal) if proposal.code_id == code_id => proposal,
+        _ => return Err(ContractError::MigrationNotApproved { code_id }),
+    };
+    if env.block.height >= proposal.expires_at {
+        return Err(ContractError::MigrationProposalExpired {})
+    }
+
+    proposal.approved = true;
+    MIGRATION_PROPOSAL.save(deps.storage, &proposal)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "approve_migration")
+        .add_attribute("code_id", code_id.to_string()))
+}
+
+// Migrate the wallet to the approved code ID. Only the contract admin can
+// migrate a contract, so with the wallet as its own admin this is the only
+// way to migrate it, and it can't be done without both parties agreeing.
+fn execute_migration(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+
+    if info.sender != state.trader && info.sender != state.funder {
+        return Err(ContractError::Unauthorized {})
+    }
+
+    let proposal = match MIGRATION_PROPOSAL.may_load(deps.storage)? {
+        Some(proposal) if proposal.approved => proposal,
+        _ => return Err(ContractError::NoApprovedMigration {}),
+    };
+    if env.block.height >= proposal.expires_at {
+        return Err(ContractError::MigrationProposalExpired {})
+    }
+
+    // Any other admin could migrate the wallet without asking either party
+    let contract = TerraQuerier::new(&deps.querier).query_contract_info(env.contract.address.as_str())?;
+    if contract.admin.as_deref() != Some(env.contract.address.as_str()) {
+        return Err(ContractError::NotOwnAdmin {})
     }
+
+    let migrate_msg = WasmMsg::Migrate {
+        contract_addr: env.contract.address.to_string(),
+        new_code_id: proposal.code_id,
+        msg: to_binary(&MigrateMsg { code_id: proposal.code_id })?,
+    };
+
+    Ok(Response::new()
+        .add_message(migrate_msg)
+        .add_attribute("method", "execute_migration")
+        .add_attribute("code_id", proposal.code_id.to_string()))
 }
 
 fn update_owner_withdrawal(
@@ -139,10 +365,12 @@ fn update_owner_withdrawal(
 
 fn send_native(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
     address: Addr,
     funds: Option<Vec<Coin>>,
     msg: Option<Binary>,
+    guard: TradeGuard,
 ) -> Result<Response, ContractError> {
     let state = STATE.load(deps.storage).unwrap();
 
@@ -151,9 +379,12 @@ fn send_native(
         return Err(ContractError::Unauthorized {})
     }
 
-    // Check if address is whitelisted
-    if !state.whitelist.contains(&address) {
-        return Err(ContractError::NotWhitelisted {})
+    // Check if address is whitelisted, for the message being sent
+    let allowed = allowed_msgs(deps.as_ref(), &state, &address)?;
+    check_variant(address.as_str(), msg.as_ref(), &allowed)?;
+    // Whatever the message pays out must come back to the wallet
+    if let Some(msg) = &msg {
+        check_recipients(msg, env.contract.address.as_str())?;
     }
 
     let mut msg_funds = vec![];
@@ -162,39 +393,42 @@ fn send_native(
         msg_funds = val;
     }
 
-    match msg {
-        Some(msg) => {
-            let final_msg = CosmosMsg::Wasm(WasmMsg::Execute{
+    let (final_msg, sent_to) = match msg {
+        Some(msg) => (
+            CosmosMsg::Wasm(WasmMsg::Execute{
                 contract_addr: address.into_string(),
                 msg,
                 funds: msg_funds,
-            });
-            return Ok(Response::new()
-                .add_attribute("method", "send_native")
-                .add_attribute("sent_to", "wasm_contract")
-                .add_message(final_msg))
-
-        },
-        None => {
-            let final_msg = CosmosMsg::Bank(BankMsg::Send {
+            }),
+            "wasm_contract",
+        ),
+        None => (
+            CosmosMsg::Bank(BankMsg::Send {
                 to_address: address.into_string(),
                 amount: msg_funds,
-            });
-            return Ok(Response::new()
-                .add_attribute("method", "send_native")
-                .add_attribute("sent_to", "wallet")
-                .add_message(final_msg))
-        }
-    }
+            }),
+            "wallet",
+        ),
+    };
+
+    snapshot_balances(deps, &env, &state, guard)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "send_native")
+        .add_attribute("sent_to", sent_to)
+        .add_submessage(SubMsg::reply_on_success(final_msg, TRADE_REPLY_ID)))
 }
 
+#[allow(clippy::too_many_arguments)]
 fn send_cw20(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
     address: Addr,
     token_addr: Addr,
     amount: Uint128,
     msg: Option<Binary>,
+    guard: TradeGuard,
 ) -> Result<Response, ContractError> {
     let state = STATE.load(deps.storage).unwrap();
 
@@ -202,382 +436,1035 @@ fn send_cw20(
         return Err(ContractError::Unauthorized {})
     }
 
-    if !state.whitelist.contains(&address) {
-        return Err(ContractError::NotWhitelisted {})
+    let allowed = allowed_msgs(deps.as_ref(), &state, &address)?;
+    check_variant(address.as_str(), msg.as_ref(), &allowed)?;
+    // Whatever the message pays out must come back to the wallet
+    if let Some(msg) = &msg {
+        check_recipients(msg, env.contract.address.as_str())?;
     }
 
-    match msg {
-        Some(msg) => {
-            let final_msg = CosmosMsg::Wasm(WasmMsg::Execute{
+    let (final_msg, sent_to) = match msg {
+        Some(msg) => (
+            CosmosMsg::Wasm(WasmMsg::Execute{
                 contract_addr: token_addr.into_string(),
                 msg: to_binary(&Cw20ExecuteMsg::Send {
                     contract: address.into_string(),
                     amount,
                     msg,})?,
                 funds: vec![],
-            }
-            );
-            return Ok(Response::new()
-                .add_attribute("method", "send_cw20")
-                .add_attribute("sent_to", "wasm_contract")
-                .add_message(final_msg))
-        },
-        None => {
-            let final_msg = CosmosMsg::Wasm(WasmMsg::Execute{
+            }),
+            "wasm_contract",
+        ),
+        None => (
+            CosmosMsg::Wasm(WasmMsg::Execute{
                 contract_addr: token_addr.into_string(),
                 msg: to_binary(&Cw20ExecuteMsg::Transfer {
                     recipient: address.into_string(),
                     amount
                 })?,
                 funds: vec![],
-            });
-            return Ok(Response::new()
-                .add_attribute("method", "send_cw20")
-                .add_attribute("sent_to", "wallet")
-                .add_message(final_msg))
+            }),
+            "wallet",
+        ),
+    };
+
+    snapshot_balances(deps, &env, &state, guard)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "send_cw20")
+        .add_attribute("sent_to", sent_to)
+        .add_submessage(SubMsg::reply_on_success(final_msg, TRADE_REPLY_ID)))
+}
+
+// Record the wallet's balances so the trade reply can see what changed
+fn snapshot_balances(
+    deps: DepsMut,
+    env: &Env,
+    state: &State,
+    guard: TradeGuard,
+) -> Result<(), ContractError> {
+    if let Some(max_loss_bps) = guard.max_loss_bps {
+        if max_loss_bps > 10_000 {
+            return Err(ContractError::InvalidMaxLoss { max_loss_bps })
         }
     }
+
+    let snapshot = TradeSnapshot {
+        assets: query_asset_balances(&deps.querier, &state.assets, env.contract.address.clone())?,
+        native: deps.querier.query_all_balances(env.contract.address.clone())?,
+        guard,
+    };
+    TRADE_SNAPSHOT.save(deps.storage, &snapshot)?;
+    Ok(())
 }
 
 fn deposit(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo
 ) -> Result<Response, ContractError> {
-    let state = STATE.load(deps.storage).unwrap();
-    // Check if address is user
-    if info.sender != state.funder {
+    let mut state = STATE.load(deps.storage).unwrap();
+    // Check if address is user, anyone may buy into a pooled wallet
+    if !state.pooled && info.sender != state.funder {
         // If sender is not user
         return Err(ContractError::Unauthorized {})
     }
 
     let funds = info.funds;
+    let invalid_deposit = || ContractError::InvalidDeposit { asset: state.base_asset.to_string() };
 
     // Check that only one kind of coin was sent
     if funds.len() != 1 {
         // If not
-        return Err(ContractError::NoLunaReceived {})
+        return Err(invalid_deposit())
     }
 
-    // Check that the currency received is Luna
-    if funds[0].denom != String::from("uluna") {
-        return Err(ContractError::NoLunaReceived {})
+    // Check that the currency received is the base asset
+    if state.base_asset != Asset::Native(funds[0].denom.clone()) {
+        return Err(invalid_deposit())
     }
     
-    let luna_sent = funds[0].amount;
+    let amount_sent = funds[0].amount;
+
+    if state.pooled {
+        let res = Response::new().add_attribute("method", "deposit");
+        return issue_shares(deps, &env, state, &info.sender, amount_sent, res)
+    }
+
+    // The fee up to now is owed on what was held before the deposit
+    settle_management_fee(deps.as_ref(), &env, &mut state, amount_sent)?;
+
+    // Add base asset amount to base_investment, and raise the
+    // high-water mark so the deposit isn't counted as profit
+    state.base_investment += amount_sent;
+    state.high_water_mark += amount_sent;
+    STATE.save(deps.storage, &state)?;
 
-    STATE.update(deps.storage, |mut state| -> Result<_, ContractError> {
-        // Add Luna amount to base_investment
-        state.base_investment = state.base_investment + luna_sent;
-        Ok(state)
-    })?;
     Ok(Response::new().add_attribute("method", "deposit"))
 }
 
-fn withdraw(
+fn receive_cw20(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
+    cw20_msg: Cw20ReceiveMsg,
+) -> Result<Response, ContractError> {
+    match from_binary(&cw20_msg.msg)? {
+        ReceiveMsg::Deposit {} => deposit_cw20(deps, env, info, cw20_msg),
+        ReceiveMsg::Redeem { split } => redeem_cw20(deps, env, info, cw20_msg, split),
+    }
+}
+
+fn deposit_cw20(
+    deps: DepsMut,
     env: Env,
-    amount: Option<Uint128>,
+    info: MessageInfo,
+    cw20_msg: Cw20ReceiveMsg,
 ) -> Result<Response, ContractError> {
     let mut state = STATE.load(deps.storage).unwrap();
 
-    if 
-        info.sender != state.funder && 
-        info.sender != state.trader && 
-        info.sender != state.trader_withdrawal_address 
-    {
+    // Check that the tokens were sent by the funder
+    let sender = deps.api.addr_validate(&cw20_msg.sender)?;
+    if !state.pooled && sender != state.funder {
         return Err(ContractError::Unauthorized {})
     }
 
-    let mut res = Response::new()
-        .add_attribute("method", "withdraw");
+    // Check that the token is one of the assets
+    let asset = Asset::Token(info.sender);
+    if !state.assets.contains(&asset) {
+        return Err(ContractError::UnlistedToken {})
+    }
 
-    let mut total_balance = Uint128::zero();
-    let mut assets: Vec<(Asset, Uint128)> = vec![];
+    let price = asset_price(&deps.querier, &state, &asset)?;
+    let value = cw20_msg.amount * price;
 
-    for asset in state.assets.clone() {
-        match asset.clone() {
-            Asset::Native(denom) => {
-                let balance = deps.querier.query_balance(env.contract.address.clone(), denom);
-                if let Ok(coin) = balance {
-                    total_balance += coin.amount;
-                    assets.push((asset, coin.amount));
-                }
-            },
-            Asset::Token(address) => {
-                let balance = query_token_balance(&deps.querier, address.clone(), env.contract.address.clone());
-                if let Ok(bal) = balance {
-                    total_balance += bal;
-                    assets.push((asset, bal));
-                }
-            },
-        }
+    if state.pooled {
+        let res = Response::new()
+            .add_attribute("method", "deposit_cw20")
+            .add_attribute("amount", cw20_msg.amount)
+            .add_attribute("value", value);
+        return issue_shares(deps, &env, state, &sender, value, res)
     }
 
-    let profit = total_balance - state.base_investment;
-    let trader_percent = Decimal::percent(state.commission.into());
-    let mut trader_funds = profit * trader_percent;
-    let mut funder_withdrawal = total_balance - trader_funds;
+    settle_management_fee(deps.as_ref(), &env, &mut state, value)?;
 
-    let mut trader_coins = vec![];
-    let mut funder_coins = vec![];
+    // Add the token's value to base_investment and the high-water mark
+    state.base_investment += value;
+    state.high_water_mark += value;
+    STATE.save(deps.storage, &state)?;
 
-    if let Some(amt) = amount {
-        funder_withdrawal = amt;
-    }
-
-    state.base_investment = total_balance - trader_funds - funder_withdrawal;
-
-    'outer: for asset in assets {
-        match asset {
-            (Asset::Native(denom), amt) => {
-                if trader_funds >= amt {
-                    // All of this asset goes to trader
-                    trader_coins.push(Coin {
-                        denom,
-                        amount: amt,
-                    });
-                    trader_funds -= amt;
-                } else if !trader_funds.is_zero() {
-                    // Only *some* of this asset goes to trader
-                    trader_coins.push(Coin {
-                        denom: denom.clone(),
-                        amount: trader_funds,
-                    });
-                    
-                    // Check for withdrawal to funder
-                    if funder_withdrawal >= amt - trader_funds {
-                        // All remaining goes to funder
-                        funder_coins.push(Coin {
-                            denom,
-                            amount: amt - trader_funds,
-                        });
-                        funder_withdrawal -= amt - trader_funds;
-                    } else if !funder_withdrawal.is_zero() {
-                        // Some of this asset goes to funder
-                        funder_coins.push(Coin {
-                            denom,
-                            amount: funder_withdrawal,
-                        });
-                        // funder_withdrawal = Uint128::zero();
-                        break 'outer
-                    }
+    Ok(Response::new()
+        .add_attribute("method", "deposit_cw20")
+        .add_attribute("amount", cw20_msg.amount)
+        .add_attribute("value", value))
+}
 
-                    trader_funds = Uint128::zero();
-                } else if funder_withdrawal >= amt {
-                    // All of this asset goes to funder
-                    funder_coins.push(Coin {
-                        denom,
-                        amount: amt,
-                    });
-                    funder_withdrawal -= amt;
-                } else if !funder_withdrawal.is_zero() {
-                    // Some of this asset goes to funder
-                    funder_coins.push(Coin {
-                        denom,
-                        amount: funder_withdrawal,
-                    });
-                    // funder_withdrawal = Uint128::zero();
-                    break 'outer;
-                } else {
-                    break 'outer;
-                }
-            },
-            (Asset::Token(addr), amt) => {
-                if trader_funds >= amt {
-                    // All of this asset goes to trader
-                    res = add_cw20_msg(
-                        res,
-                        addr,
-                        state.trader_withdrawal_address.clone(),
-                        amt,
-                        None,
-                    );
-                    trader_funds -= amt;
-                } else if !trader_funds.is_zero() {
-                    // Only *some* of this asset goes to trader
-                    res = add_cw20_msg(
-                        res,
-                        addr.clone(),
-                        state.trader_withdrawal_address.clone(),
-                        trader_funds,
-                        None
-                    );
-
-                    // Check for withdrawal to funder
-                    if funder_withdrawal >= amt - trader_funds {
-                        // All remaining goes to funder
-                        res = add_cw20_msg(
-                            res,
-                            addr,
-                            state.funder.clone(),
-                            amt - trader_funds,
-                            None,
-                        );
-                        funder_withdrawal -= amt - trader_funds;
-                    } else if !funder_withdrawal.is_zero() {
-                        // Some of this asset goes to funder
-                        res = add_cw20_msg(
-                            res,
-                            addr,
-                            state.funder.clone(),
-                            funder_withdrawal,
-                            None,
-                        );
-                        // funder_withdrawal = Uint128::zero();
-                        break 'outer;
-                    }
-                } else if funder_withdrawal >= amt {
-                    // All of this asset goes to funder
-                    res = add_cw20_msg(
-                        res,
-                        addr,
-                        state.funder.clone(),
-                        amt,
-                        None,
-                    );
-                    funder_withdrawal -= amt;
-                } else if !funder_withdrawal.is_zero() {
-                    // Some of this asset goes to funder
-                    res = add_cw20_msg(
-                        res,
-                        addr,
-                        state.funder.clone(),
-                        funder_withdrawal,
-                        None,
-                    );
-                    // funder_withdrawal = Uint128::zero();
-                    break 'outer;
-                } else {
-                    break 'outer;
-                }
-            },
-        }
-    }
+// Issue shares in a pooled wallet for a deposit worth `value`, which has
+// already arrived, priced at the wallet's value before the deposit. With
+// a share token, the shares are minted to the funder.
+fn issue_shares(
+    deps: DepsMut,
+    env: &Env,
+    mut state: State,
+    funder: &Addr,
+    value: Uint128,
+    res: Response,
+) -> Result<Response, ContractError> {
+    let assets = query_asset_values(&deps.querier, &state, env.contract.address.clone())?;
+    let held = total_value(&assets).saturating_sub(value);
+    let now = env.block.time.seconds();
 
-    // Send coins
-    if trader_coins.len() > 0 {
-        res = res.add_message(CosmosMsg::Bank(BankMsg::Send {
-            to_address: state.trader_withdrawal_address.to_string(),
-            amount: trader_coins,
-        }));
+    // Share tokens change hands without the wallet seeing it, so commission
+    // can't follow each holder's entry price. It is crystallized for the
+    // whole pool instead, before the deposit buys in net of it.
+    let commission = if state.share_token.is_some() && !state.total_shares.is_zero() {
+        state.crystallize_commission(held, now)
+    } else {
+        state.accrue_management_fee(held, now);
+        Uint128::zero()
     };
-    if funder_coins.len() > 0 {
-        res = res.add_message(CosmosMsg::Bank(BankMsg::Send {
-            to_address: state.funder.to_string(),
-            amount: funder_coins,
-        }));
+    let nav = held.saturating_sub(state.accrued_fee).saturating_sub(commission);
+
+    // Shares in a wallet worth nothing can't be priced, and new ones would
+    // dilute the holders' claim on anything it later recovers
+    if !state.total_shares.is_zero() && nav.is_zero() {
+        return Err(ContractError::ZeroNav {})
+    }
+
+    // The first deposit sets shares one to one with the base denom
+    let shares = if state.total_shares.is_zero() {
+        value
+    } else {
+        value.multiply_ratio(state.total_shares, nav)
     };
+    // Rounding must not let a deposit buy nothing and go to the other holders
+    if shares.is_zero() {
+        return Err(ContractError::ZeroShares {})
+    }
+
+    if state.share_token.is_some() {
+        state.high_water_mark += value;
+    } else {
+        let mut position = FUNDERS.may_load(deps.storage, funder)?.unwrap_or_default();
+        position.shares += shares;
+        position.cost_basis += value;
+        FUNDERS.save(deps.storage, funder, &position)?;
+    }
 
+    state.total_shares += shares;
+    state.base_investment += value;
     STATE.save(deps.storage, &state)?;
 
+    let mut res = res.add_attribute("shares", shares);
+    if !commission.is_zero() {
+        let payouts = split_assets(WithdrawSplit::default(), assets, commission, Uint128::zero(), &[], &[]);
+        res = add_asset_msgs(res.add_attribute("commission", commission), state.trader_withdrawal_address.clone(), payouts.trader);
+    }
+    if let Some(token) = state.share_token {
+        res = res.add_message(WasmMsg::Execute {
+            contract_addr: token.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Mint { recipient: funder.to_string(), amount: shares })?,
+            funds: vec![],
+        });
+    }
     Ok(res)
 }
 
-fn toggle_lock(
+fn redeem(
     deps: DepsMut,
-    info: MessageInfo
+    env: Env,
+    info: MessageInfo,
+    shares: Option<Uint128>,
+    split: Option<WithdrawSplit>,
 ) -> Result<Response, ContractError> {
-    let mut state = STATE.load(deps.storage).unwrap();
-
-    if info.sender == state.funder {
-        state.funder_lock = !state.funder_lock;
-        STATE.save(deps.storage, &state)?;
-        return Ok(Response::new()
-            .add_attribute("method", "toggle_lock")
-            .add_attribute("funder_lock", state.funder_lock.to_string())
-            .add_attribute("trader_lock", state.trader_lock.to_string())
-            .add_attribute("locked", (state.trader_lock || state.funder_lock).to_string()))
+    let state = STATE.load(deps.storage).unwrap();
+    if !state.pooled {
+        return Err(ContractError::NotPooled {})
     }
-
-    if info.sender == state.trader {
-        state.trader_lock = !state.trader_lock;
-        STATE.save(deps.storage, &state)?;
-        return Ok(Response::new()
-            .add_attribute("method", "toggle_lock")
-            .add_attribute("funder_lock", state.funder_lock.to_string())
-            .add_attribute("trader_lock", state.trader_lock.to_string())
-            .add_attribute("locked", (state.trader_lock || state.funder_lock).to_string()))
+    // Tokenized shares are redeemed by sending them back to the wallet
+    if state.share_token.is_some() {
+        return Err(ContractError::RedeemShareToken {})
     }
 
-    Err(ContractError::Unauthorized {})
+    let position = FUNDERS.may_load(deps.storage, &info.sender)?.unwrap_or_default();
+    let shares = shares.unwrap_or(position.shares);
+    if shares.is_zero() || shares > position.shares {
+        return Err(ContractError::InsufficientShares { shares, held: position.shares })
+    }
 
+    redeem_shares(deps, env, state, info.sender, shares, split)
 }
 
-fn update_state(
+fn redeem_cw20(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
+    cw20_msg: Cw20ReceiveMsg,
+    split: Option<WithdrawSplit>,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+
+    // Only the wallet's own share token can be redeemed
+    let token = match &state.share_token {
+        Some(token) if token == &info.sender => token.clone(),
+        _ => return Err(ContractError::UnlistedToken {}),
+    };
+    let holder = deps.api.addr_validate(&cw20_msg.sender)?;
+    let res = redeem_shares(deps, env, state, holder, cw20_msg.amount, split)?;
+    Ok(res.add_message(WasmMsg::Execute {
+        contract_addr: token.to_string(),
+        msg: to_binary(&Cw20ExecuteMsg::Burn { amount: cw20_msg.amount })?,
+        funds: vec![],
+    }))
+}
+
+// Pay `holder` the value of `shares`, less commission. Recorded shares
+// pay commission on what they gained since the funder bought them, and
+// share tokens pay the commission crystallized for the whole pool.
+fn redeem_shares(
+    deps: DepsMut,
     env: Env,
-    whitelist: Option<Vec<Addr>>,
-    assets: Option<Vec<Asset>>,
-    commission: Option<u8>,
-    user: Option<Addr>,
+    mut state: State,
+    holder: Addr,
+    shares: Uint128,
+    split: Option<WithdrawSplit>,
 ) -> Result<Response, ContractError> {
-    let mut state = STATE.load(deps.storage).unwrap();
+    let assets = query_asset_values(&deps.querier, &state, env.contract.address.clone())?;
+    let total_balance = total_value(&assets);
+    let now = env.block.time.seconds();
 
-    // If sender is trader and funder has locked
-    if info.sender == state.trader {
-        if state.funder_lock {
-            return Err(ContractError::Locked {})
-        }
-    // If sender is funder and trader has locked
-    } else if info.sender == state.funder {
-        if state.trader_lock {
-            return Err(ContractError::Locked {})
-        }
-    // If sender is neither of funder or trader
+    // The management fee and any pool commission are paid out first, and
+    // shares redeem at what is left
+    let pool_commission = if state.share_token.is_some() {
+        state.crystallize_commission(total_balance, now)
     } else {
-        return Err(ContractError::Unauthorized {})
-    }
-
-    // Update all included values in state
-    if let Some(val) = whitelist {
-        state.whitelist = val;
+        state.accrue_management_fee(total_balance, now);
+        Uint128::zero()
     };
-    if let Some(val) = assets {
-        state.assets = val;
-    }
-    if let Some(val) = commission {
-        if val >= MINIMUM_COMMISSION {
-            state.commission = val;
+    let fee = state.accrued_fee.min(total_balance);
+    state.accrued_fee = Uint128::zero();
+    let nav = (total_balance - fee).saturating_sub(pool_commission);
+    let value = nav.multiply_ratio(shares, state.total_shares);
+    let remaining = state.total_shares - shares;
+
+    let commission = if state.share_token.is_some() {
+        let basis = state.base_investment.multiply_ratio(shares, state.total_shares);
+        state.high_water_mark = state.high_water_mark.multiply_ratio(remaining, state.total_shares);
+        state.base_investment -= basis;
+        state.realized_profit += value.saturating_sub(basis);
+        pool_commission
+    } else {
+        let mut position = FUNDERS.may_load(deps.storage, &holder)?.unwrap_or_default();
+        let basis = position.cost_basis.multiply_ratio(shares, position.shares);
+        let profit = value.saturating_sub(basis);
+        let commission = state.commission_on(profit, basis);
+
+        position.shares -= shares;
+        position.cost_basis -= basis;
+        if position.shares.is_zero() {
+            FUNDERS.remove(deps.storage, &holder);
+        } else {
+            FUNDERS.save(deps.storage, &holder, &position)?;
         }
+        state.base_investment = state.base_investment.saturating_sub(basis);
+        state.realized_profit += profit - commission;
+        commission
     };
-    if let Some(val) = user {
-        // Check that there are currently no funds in the contract
-        let mut total_balance = Uint128::zero();
-        for asset in state.assets.clone() {
-            match asset {
-                Asset::Native(denom) => {
-                    let balance = deps.querier.query_balance(env.contract.address.clone(), denom);
-                    if let Ok(coin) = balance {
-                        total_balance += coin.amount;
-                    }
-                },
-                Asset::Token(address) => {
-                    let balance = query_token_balance(&deps.querier, address.clone(), env.contract.address.clone());
-                    if let Ok(bal) = balance {
-                        total_balance += bal;
-                    }
-                },
-            }
-        }        if total_balance.is_zero() {
-            state.funder = val;
-        }
-    }
+    state.total_shares = remaining;
+
+    // Pool commission has already come out of the shares' value
+    let payout = value - (commission - pool_commission);
+    let payouts = split_assets(
+        split.unwrap_or_default(),
+        assets,
+        fee + commission,
+        payout,
+        &[],
+        &[],
+    );
+    let res = Response::new()
+        .add_attribute("method", "redeem")
+        .add_attribute("shares", shares)
+        .add_attribute("commission", commission);
+    let res = add_asset_msgs(res, state.trader_withdrawal_address.clone(), payouts.trader);
+    let res = add_asset_msgs(res, holder, payouts.funder);
 
     STATE.save(deps.storage, &state)?;
 
-    Ok(Response::new().add_attribute("method", "update_state"))
+    Ok(res)
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
+fn withdraw(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    amount: Option<Uint128>,
+    split: Option<WithdrawSplit>,
+    preference: Option<Vec<Asset>>,
+    min_out: Option<Vec<AssetAmount>>,
+) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage).unwrap();
+    if state.pooled {
+        return Err(ContractError::Pooled {})
+    }
+
+    if 
+        info.sender != state.funder && 
+        info.sender != state.trader && 
+        info.sender != state.trader_withdrawal_address 
+    {
+        return Err(ContractError::Unauthorized {})
+    }
+
+    let preference = preference.unwrap_or_default();
+    let min_out = min_out.unwrap_or_default();
+    for asset in preference.iter().chain(min_out.iter().map(|m| &m.asset)) {
+        if !state.assets.contains(asset) {
+            return Err(ContractError::UnlistedAsset { asset: asset.to_string() })
+        }
+    }
+
+    let res = Response::new()
+        .add_attribute("method", "withdraw");
+
+    // All amounts below are valued in the base denom
+    let assets = query_asset_values(&deps.querier, &state, env.contract.address.clone())?;
+    let total_balance = total_value(&assets);
+
+    // The management fee is settled first, and paid out with the commission
+    let commission = state.crystallize_commission(total_balance, env.block.time.seconds());
+    let fee = state.accrued_fee.min(total_balance);
+    state.accrued_fee = Uint128::zero();
+
+    let trader_funds = commission + fee;
+    let available = total_balance - trader_funds;
+    let mut funder_withdrawal = available;
+
+    if let Some(amt) = amount {
+        funder_withdrawal = amt.min(available);
+    }
+
+    // The funder's withdrawal takes principal and unrealized profit in
+    // proportion, and the mark shrinks to match
+    let remaining = available - funder_withdrawal;
+    let unrealized = available.saturating_sub(state.base_investment);
+    if available.is_zero() {
+        state.base_investment = Uint128::zero();
+        state.unrealized_profit = Uint128::zero();
+        state.high_water_mark = Uint128::zero();
+    } else {
+        state.base_investment = state.base_investment.multiply_ratio(remaining, available);
+        state.unrealized_profit = unrealized.multiply_ratio(remaining, available);
+        state.high_water_mark = state.high_water_mark.multiply_ratio(remaining, available);
+    }
+    state.realized_profit += unrealized - state.unrealized_profit;
+
+    // The caller's preference applies to their own side of the split
+    let is_funder = info.sender == state.funder;
+    let (trader_preference, funder_preference) = if is_funder {
+        (vec![], preference)
+    } else {
+        (preference, vec![])
+    };
+    let payouts = split_assets(
+        split.unwrap_or_default(),
+        assets,
+        trader_funds,
+        funder_withdrawal,
+        &trader_preference,
+        &funder_preference,
+    );
+
+    // Check the caller receives at least their minimum of each asset
+    let received = if is_funder { &payouts.funder } else { &payouts.trader };
+    for min in min_out {
+        let amount: Uint128 = received.iter()
+            .filter(|(asset, _)| asset == &min.asset)
+            .map(|(_, amount)| *amount)
+            .sum();
+        if amount < min.amount {
+            return Err(ContractError::MinimumOutNotMet {
+                asset: min.asset.to_string(),
+                amount,
+                min: min.amount,
+            })
+        }
+    }
+
+    // Send coins
+    let res = add_asset_msgs(res, state.trader_withdrawal_address.clone(), payouts.trader);
+    let res = add_asset_msgs(res, state.funder.clone(), payouts.funder);
+
+    STATE.save(deps.storage, &state)?;
+
+    Ok(res)
+}
+
+fn claim_commission(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage).unwrap();
+
+    if info.sender != state.trader {
+        return Err(ContractError::Unauthorized {})
+    }
+    // Pooled commission is paid as each funder redeems
+    if state.pooled {
+        return Err(ContractError::Pooled {})
+    }
+
+    let assets = query_asset_values(&deps.querier, &state, env.contract.address.clone())?;
+    let total_balance = total_value(&assets);
+    let commission = state.crystallize_commission(total_balance, env.block.time.seconds());
+    state.unrealized_profit = (total_balance - commission)
+        .saturating_sub(state.accrued_fee)
+        .saturating_sub(state.base_investment);
+
+    // Only the commission leaves, everything else stays invested
+    let payouts = split_assets(WithdrawSplit::Waterfall, assets, commission, Uint128::zero(), &[], &[]);
+    let res = Response::new()
+        .add_attribute("method", "claim_commission")
+        .add_attribute("commission", commission);
+    let res = add_asset_msgs(res, state.trader_withdrawal_address.clone(), payouts.trader);
+
+    STATE.save(deps.storage, &state)?;
+
+    Ok(res)
+}
+
+fn claim_management_fee(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage).unwrap();
+
+    if info.sender != state.trader {
+        return Err(ContractError::Unauthorized {})
+    }
+
+    let assets = query_asset_values(&deps.querier, &state, env.contract.address.clone())?;
+    state.accrue_management_fee(total_value(&assets), env.block.time.seconds());
+    let fee = state.accrued_fee.min(total_value(&assets));
+    state.accrued_fee = Uint128::zero();
+
+    let payouts = split_assets(WithdrawSplit::Waterfall, assets, fee, Uint128::zero(), &[], &[]);
+    let res = Response::new()
+        .add_attribute("method", "claim_management_fee")
+        .add_attribute("fee", fee);
+    let res = add_asset_msgs(res, state.trader_withdrawal_address.clone(), payouts.trader);
+
+    STATE.save(deps.storage, &state)?;
+
+    Ok(res)
+}
+
+// Accrue the management fee up to the current block, on the value held
+// less an `inflow` that has only just arrived
+fn settle_management_fee(
+    deps: Deps,
+    env: &Env,
+    state: &mut State,
+    inflow: Uint128,
+) -> StdResult<()> {
+    let now = env.block.time.seconds();
+    if state.management_fee.is_none() {
+        state.last_fee_accrual = now;
+        return Ok(());
+    }
+    let assets = query_asset_values(&deps.querier, state, env.contract.address.clone())?;
+    state.accrue_management_fee(total_value(&assets).saturating_sub(inflow), now);
+    Ok(())
+}
+
+fn toggle_lock(
+    deps: DepsMut,
+    info: MessageInfo
+) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage).unwrap();
+
+    if info.sender == state.funder {
+        state.funder_lock = !state.funder_lock;
+        STATE.save(deps.storage, &state)?;
+        return Ok(Response::new()
+            .add_attribute("method", "toggle_lock")
+            .add_attribute("funder_lock", state.funder_lock.to_string())
+            .add_attribute("trader_lock", state.trader_lock.to_string())
+            .add_attribute("locked", (state.trader_lock || state.funder_lock).to_string()))
+    }
+
+    if info.sender == state.trader {
+        state.trader_lock = !state.trader_lock;
+        STATE.save(deps.storage, &state)?;
+        return Ok(Response::new()
+            .add_attribute("method", "toggle_lock")
+            .add_attribute("funder_lock", state.funder_lock.to_string())
+            .add_attribute("trader_lock", state.trader_lock.to_string())
+            .add_attribute("locked", (state.trader_lock || state.funder_lock).to_string()))
+    }
+
+    Err(ContractError::Unauthorized {})
+
+}
+
+// Only the trader and funder may change the configuration, and neither
+// while the other has locked it
+fn check_can_update(state: &State, sender: &Addr) -> Result<(), ContractError> {
+    // If sender is trader and funder has locked
+    if sender == &state.trader {
+        if state.funder_lock {
+            return Err(ContractError::Locked {})
+        }
+    // If sender is funder and trader has locked
+    } else if sender == &state.funder {
+        if state.trader_lock {
+            return Err(ContractError::Locked {})
+        }
+    // If sender is neither of funder or trader
+    } else {
+        return Err(ContractError::Unauthorized {})
+    }
+    Ok(())
+}
+
+fn add_whitelist(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: Addr,
+    allowed_msgs: Option<Vec<String>>,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+    check_can_update(&state, &info.sender)?;
+
+    // A contract is pinned to the code it runs now, and only an address
+    // known not to be a contract is left unpinned
+    let address = deps.api.addr_validate(address.as_str())?;
+    let code_id = query_code_id(&deps.querier, &address)?;
+    WHITELIST.save(deps.storage, &address, &WhitelistEntry { allowed_msgs, code_id })?;
+
+    Ok(Response::new()
+        .add_attribute("method", "add_whitelist")
+        .add_attribute("address", address))
+}
+
+fn remove_whitelist(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: Addr,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+    check_can_update(&state, &info.sender)?;
+
+    if !WHITELIST.has(deps.storage, &address) {
+        return Err(ContractError::NotWhitelisted {})
+    }
+    WHITELIST.remove(deps.storage, &address);
+
+    Ok(Response::new()
+        .add_attribute("method", "remove_whitelist")
+        .add_attribute("address", address))
+}
+
+fn add_code_id_whitelist(
+    deps: DepsMut,
+    info: MessageInfo,
+    code_id: u64,
+    allowed_msgs: Option<Vec<String>>,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+    check_can_update(&state, &info.sender)?;
+
+    CODE_ID_WHITELIST.save(deps.storage, U64Key::new(code_id), &WhitelistRule { allowed_msgs })?;
+
+    Ok(Response::new()
+        .add_attribute("method", "add_code_id_whitelist")
+        .add_attribute("code_id", code_id.to_string()))
+}
+
+fn remove_code_id_whitelist(
+    deps: DepsMut,
+    info: MessageInfo,
+    code_id: u64,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+    check_can_update(&state, &info.sender)?;
+
+    if !CODE_ID_WHITELIST.has(deps.storage, U64Key::new(code_id)) {
+        return Err(ContractError::NotWhitelisted {})
+    }
+    CODE_ID_WHITELIST.remove(deps.storage, U64Key::new(code_id));
+
+    Ok(Response::new()
+        .add_attribute("method", "remove_code_id_whitelist")
+        .add_attribute("code_id", code_id.to_string()))
+}
+
+fn add_factory_whitelist(
+    deps: DepsMut,
+    info: MessageInfo,
+    factory: Addr,
+    allowed_msgs: Option<Vec<String>>,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+    check_can_update(&state, &info.sender)?;
+
+    let factory = deps.api.addr_validate(factory.as_str())?;
+    FACTORY_WHITELIST.save(deps.storage, &factory, &WhitelistRule { allowed_msgs })?;
+
+    Ok(Response::new()
+        .add_attribute("method", "add_factory_whitelist")
+        .add_attribute("factory", factory))
+}
+
+fn remove_factory_whitelist(
+    deps: DepsMut,
+    info: MessageInfo,
+    factory: Addr,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage).unwrap();
+    check_can_update(&state, &info.sender)?;
+
+    if !FACTORY_WHITELIST.has(deps.storage, &factory) {
+        return Err(ContractError::NotWhitelisted {})
+    }
+    FACTORY_WHITELIST.remove(deps.storage, &factory);
+
+    Ok(Response::new()
+        .add_attribute("method", "remove_factory_whitelist")
+        .add_attribute("factory", factory))
+}
+
+// Replace the price source of an asset once it fails to answer, such as an
+// oracle that was removed or migrated. Every valuation needs every price,
+// so a locked wallet would otherwise keep the funder from withdrawing.
+fn replace_price_source(
+    deps: DepsMut,
+    info: MessageInfo,
+    asset: Asset,
+    source: PriceSource,
+) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage).unwrap();
+    if info.sender != state.trader && info.sender != state.funder {
+        return Err(ContractError::Unauthorized {})
+    }
+
+    let price = state.price_sources
+        .iter_mut()
+        .find(|p| p.asset == asset)
+        .ok_or(ContractError::UnlistedPriceSource {})?;
+    if query_price(&deps.querier, &price.source).is_ok() {
+        return Err(ContractError::PriceSourceAnswers { asset: asset.to_string() })
+    }
+    // The replacement must answer itself
+    query_price(&deps.querier, &source)?;
+    price.source = source;
+    STATE.save(deps.storage, &state)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "replace_price_source")
+        .add_attribute("asset", asset.to_string()))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_state(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    assets: Option<Vec<Asset>>,
+    commission: Option<Decimal>,
+    commission_tiers: Option<Vec<CommissionTier>>,
+    user: Option<Addr>,
+    price_sources: Option<Vec<AssetPrice>>,
+    hurdle_rate: Option<Decimal>,
+    management_fee: Option<Decimal>,
+) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage).unwrap();
+    check_can_update(&state, &info.sender)?;
+
+    // Update all included values in state
+    if let Some(val) = assets {
+        state.assets = val;
+        // The base asset is always held, as at instantiation
+        if !state.assets.contains(&state.base_asset) {
+            state.assets.insert(0, state.base_asset.clone());
+        }
+    }
+    if let Some(val) = price_sources {
+        state.price_sources = val;
+    }
+    validate_price_sources(&state)?;
+    if let Some(val) = management_fee {
+        // Settle what is owed at the old rate before the new one applies
+        settle_management_fee(deps.as_ref(), &env, &mut state, Uint128::zero())?;
+        state.management_fee = Some(val);
+    }
+    if let Some(val) = hurdle_rate {
+        // The new hurdle only applies from now on
+        state.hurdle_rate = Some(val);
+        state.last_crystallization = env.block.time.seconds();
+    }
+    if let Some(val) = commission {
+        state.commission = val;
+    };
+    if let Some(val) = commission_tiers {
+        state.commission_tiers = val;
+    }
+    validate_commission(&state)?;
+    if let Some(val) = user {
+        // Check that there are currently no funds in the contract
+        let mut total_balance = Uint128::zero();
+        for asset in state.assets.clone() {
+            match asset {
+                Asset::Native(denom) => {
+                    let balance = deps.querier.query_balance(env.contract.address.clone(), denom);
+                    if let Ok(coin) = balance {
+                        total_balance += coin.amount;
+                    }
+                },
+                Asset::Token(address) => {
+                    let balance = query_token_balance(&deps.querier, address.clone(), env.contract.address.clone());
+                    if let Ok(bal) = balance {
+                        total_balance += bal;
+                    }
+                },
+            }
+        }        if total_balance.is_zero() {
+            state.funder = val;
+        }
+    }
+
+    STATE.save(deps.storage, &state)?;
+
+    Ok(Response::new().add_attribute("method", "update_state"))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(
+    deps: DepsMut,
+    env: Env,
+    msg: MigrateMsg,
+) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME && stored.contract != LEGACY_CONTRACT_NAME {
+        return Err(ContractError::WrongContract { contract: stored.contract })
+    }
+
+    let stored_version = parse_version(&stored.version)?;
+    let new_version = parse_version(CONTRACT_VERSION)?;
+    if stored_version > new_version {
+        return Err(ContractError::CannotDowngrade {
+            stored: stored.version,
+            new: CONTRACT_VERSION.to_string(),
+        })
+    }
+
+    // Both parties must have agreed to the migration, unless the stored
+    // version predates migration proposals. This only holds for code that
+    // keeps the check, the wallet being its own admin is what enforces it.
+    if stored_version >= parse_version(PROPOSALS_VERSION)? {
+        match MIGRATION_PROPOSAL.may_load(deps.storage)? {
+            Some(proposal) if proposal.code_id == msg.code_id && proposal.approved => {
+                if env.block.height >= proposal.expires_at {
+                    return Err(ContractError::MigrationProposalExpired {})
+                }
+            },
+            _ => return Err(ContractError::MigrationNotApproved { code_id: msg.code_id }),
+        }
+    }
+    MIGRATION_PROPOSAL.remove(deps.storage);
+
+    let applied = migrations::run(deps.storage, &stored_version)?;
+    // Migrations can't query, so entries carried over unpinned are pinned here
+    pin_whitelist(deps.storage, &deps.querier)?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION)
+        .add_attribute("migrations", applied.join(",")))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+) -> Result<Response, ContractError> {
+    match msg.id {
+        TRADE_REPLY_ID => verify_trade(deps, env, msg),
+        SHARE_TOKEN_REPLY_ID => save_share_token(deps, msg),
+        id => Err(ContractError::UnknownReplyId { id }),
+    }
+}
+
+fn verify_trade(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    let snapshot = TRADE_SNAPSHOT.load(deps.storage)?;
+    TRADE_SNAPSHOT.remove(deps.storage);
+
+    let after = query_asset_balances(&deps.querier, &state.assets, env.contract.address.clone())?;
+
+    // Check the combined balance against the trader's limits
+    let nav_before = total_value(&value_balances(&deps.querier, &state, snapshot.assets.clone())?);
+    let nav_after = total_value(&value_balances(&deps.querier, &state, after.clone())?);
+    if let Some(min_nav) = snapshot.guard.min_nav_after {
+        if nav_after < min_nav {
+            return Err(ContractError::NavBelowMinimum { nav: nav_after, min_nav })
+        }
+    }
+    if let Some(max_loss_bps) = snapshot.guard.max_loss_bps {
+        if nav_after < nav_before {
+            let loss_bps = (nav_before - nav_after).multiply_ratio(10_000u128, nav_before);
+            if loss_bps > Uint128::from(max_loss_bps) {
+                return Err(ContractError::MaxLossExceeded { loss_bps, max_loss_bps })
+            }
+        }
+    }
+
+    // Nothing left the approved assets, so nothing can have been swapped away
+    let value_out = snapshot.assets.iter().any(|(asset, before)| {
+        after.iter().any(|(a, now)| a == asset && now < before)
+    });
+    if !value_out {
+        return Ok(Response::new().add_attribute("method", "verify_trade"));
+    }
+
+    // Native coins are checked against every balance held before the trade
+    for coin in deps.querier.query_all_balances(env.contract.address.clone())? {
+        if state.assets.contains(&Asset::Native(coin.denom.clone())) {
+            continue;
+        }
+        let before = snapshot.native.iter()
+            .find(|c| c.denom == coin.denom)
+            .map(|c| c.amount)
+            .unwrap_or_else(Uint128::zero);
+        if coin.amount > before {
+            return Err(ContractError::UnapprovedAssetReceived { asset: coin.denom })
+        }
+    }
+
+    // CW20 balances can't be listed, so look for tokens credited to us in the trade's events
+    if let ContractResult::Ok(response) = msg.result {
+        for token in cw20_receipts(&response.events, env.contract.address.as_str()) {
+            if !state.assets.contains(&Asset::Token(Addr::unchecked(token.clone()))) {
+                return Err(ContractError::UnapprovedAssetReceived { asset: token })
+            }
+        }
+    }
+
+    Ok(Response::new().add_attribute("method", "verify_trade"))
+}
+
+// Tokens credited to `wallet` by the cw20 actions in `events`. wasmd emits
+// a `wasm` event per contract starting with `_contract_address`, while
+// Terra puts every contract's attributes in one `from_contract` event,
+// each starting with `contract_address`. Tokens that don't report the
+// standard cw20 `action` and `to`/`recipient` attributes aren't seen.
+fn cw20_receipts(events: &[Event], wallet: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    for event in events.iter().filter(|e| e.ty == "wasm" || e.ty == "from_contract") {
+        // Split the attributes into the run each contract emitted
+        let mut runs: Vec<Vec<&Attribute>> = vec![];
+        for attr in &event.attributes {
+            if attr.key == "_contract_address" || attr.key == "contract_address" || runs.is_empty() {
+                runs.push(vec![]);
+            }
+            runs.last_mut().unwrap().push(attr);
+        }
+
+        for run in runs {
+            let attr = |key: &str| run.iter()
+                .find(|a| a.key == key)
+                .map(|a| a.value.clone());
+
+            let action = attr("action").unwrap_or_default();
+            if !CW20_RECEIVE_ACTIONS.contains(&action.as_str()) {
+                continue;
+            }
+            let recipient = attr("to").or_else(|| attr("recipient"));
+            if recipient.as_deref() != Some(wallet) {
+                continue;
+            }
+            if let Some(token) = attr("_contract_address").or_else(|| attr("contract_address")) {
+                tokens.push(token);
+            }
+        }
+    }
+    tokens
+}
+
+// Read the share token's address from its instantiation events
+fn save_share_token(
+    deps: DepsMut,
+    msg: Reply,
+) -> Result<Response, ContractError> {
+    let response = match msg.result {
+        ContractResult::Ok(response) => response,
+        ContractResult::Err(err) => return Err(StdError::generic_err(err).into()),
+    };
+    let address = response.events.iter()
+        .filter(|e| e.ty == "instantiate_contract" || e.ty == "instantiate")
+        .flat_map(|e| e.attributes.iter())
+        .find(|a| a.key == "contract_address" || a.key == "_contract_address")
+        .map(|a| a.value.clone())
+        .ok_or_else(|| StdError::generic_err("Share token address not found in instantiate events"))?;
+    let token = deps.api.addr_validate(&address)?;
+
+    let mut state = STATE.load(deps.storage)?;
+    state.share_token = Some(token.clone());
+    STATE.save(deps.storage, &state)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "save_share_token")
+        .add_attribute("share_token", token))
+}
+
+// Every commission rate must be within the instance's bounds, and tier
+// thresholds must increase
+fn validate_commission(state: &State) -> Result<(), ContractError> {
+    let rates = std::iter::once(state.commission)
+        .chain(state.commission_tiers.iter().map(|tier| tier.commission));
+    for commission in rates {
+        if commission < state.min_commission || commission > state.max_commission {
+            return Err(ContractError::CommissionOutOfRange {
+                commission,
+                min: state.min_commission,
+                max: state.max_commission,
+            })
+        }
+    }
+
+    let mut last_threshold = Decimal::zero();
+    for tier in &state.commission_tiers {
+        if tier.threshold <= last_threshold {
+            return Err(ContractError::InvalidCommissionTiers {})
+        }
+        last_threshold = tier.threshold;
+    }
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
 pub fn query(
     deps: Deps,
-    _env: Env,
+    env: Env,
     msg: QueryMsg
 ) -> StdResult<Binary> {
     match msg {
         QueryMsg::GetState {} => to_binary(&query_state(deps)?),
+        QueryMsg::GetMigrationProposal {} => to_binary(&query_migration_proposal(deps)?),
+        QueryMsg::GetManagementFee {} => to_binary(&query_management_fee(deps, env)?),
+        QueryMsg::ListFunders { start_after, limit } => to_binary(&query_funders(deps, start_after, limit)?),
+        QueryMsg::ListWhitelist { start_after, limit } => to_binary(&query_whitelist(deps, start_after, limit)?),
+        QueryMsg::ListCodeIdWhitelist { start_after, limit } => to_binary(&query_code_id_whitelist(deps, start_after, limit)?),
+        QueryMsg::ListFactoryWhitelist { start_after, limit } => to_binary(&query_factory_whitelist(deps, start_after, limit)?),
     }
 }
 
@@ -586,6 +1473,1235 @@ fn query_state(deps: Deps) -> StdResult<StateResponse> {
     Ok(StateResponse { state })
 }
 
+fn query_management_fee(deps: Deps, env: Env) -> StdResult<ManagementFeeResponse> {
+    let mut state = STATE.load(deps.storage)?;
+    settle_management_fee(deps, &env, &mut state, Uint128::zero())?;
+    Ok(ManagementFeeResponse {
+        management_fee: state.management_fee,
+        accrued_fee: state.accrued_fee,
+    })
+}
+
+fn query_funders(
+    deps: Deps,
+    start_after: Option<Addr>,
+    limit: Option<u32>,
+) -> StdResult<FundersResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|addr| Bound::exclusive(addr.as_bytes()));
+    let funders = FUNDERS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (key, position) = item?;
+            Ok(FunderResponse {
+                address: Addr::unchecked(String::from_utf8(key)?),
+                shares: position.shares,
+                cost_basis: position.cost_basis,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(FundersResponse { funders })
+}
+
+fn query_whitelist(
+    deps: Deps,
+    start_after: Option<Addr>,
+    limit: Option<u32>,
+) -> StdResult<WhitelistResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|addr| Bound::exclusive(addr.as_bytes()));
+    let entries = WHITELIST
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (key, entry) = item?;
+            Ok(WhitelistEntryResponse {
+                address: Addr::unchecked(String::from_utf8(key)?),
+                allowed_msgs: entry.allowed_msgs,
+                code_id: entry.code_id,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(WhitelistResponse { entries })
+}
+
+fn query_code_id_whitelist(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<CodeIdWhitelistResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive_int);
+    let rules = CODE_ID_WHITELIST
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (key, rule) = item?;
+            let code_id = key.try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| StdError::generic_err("Invalid code ID key"))?;
+            Ok(CodeIdRuleResponse { code_id, allowed_msgs: rule.allowed_msgs })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(CodeIdWhitelistResponse { rules })
+}
+
+fn query_factory_whitelist(
+    deps: Deps,
+    start_after: Option<Addr>,
+    limit: Option<u32>,
+) -> StdResult<FactoryWhitelistResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|addr| Bound::exclusive(addr.as_bytes()));
+    let rules = FACTORY_WHITELIST
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (key, rule) = item?;
+            Ok(FactoryRuleResponse {
+                factory: Addr::unchecked(String::from_utf8(key)?),
+                allowed_msgs: rule.allowed_msgs,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(FactoryWhitelistResponse { rules })
+}
+
+fn query_migration_proposal(deps: Deps) -> StdResult<MigrationProposalResponse> {
+    let proposal = MIGRATION_PROPOSAL.may_load(deps.storage)?;
+    Ok(MigrationProposalResponse { proposal })
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR};
+    use cosmwasm_std::{attr, coin, coins, from_slice, Event, SubMsgExecutionResponse, OwnedDeps, Querier, QuerierResult, QueryRequest, SystemError, SystemResult, WasmQuery};
+    use cw20::BalanceResponse;
+    use terra_cosmwasm::{ContractInfoResponse, TerraQuery, TerraQueryWrapper};
+    use serde::Serialize;
+    use crate::msg::ShareTokenConfig;
+    use crate::valuation::{HubRateField, PriceResponse};
+    use crate::whitelist::{AssetInfo, FactoryQueryMsg, PairInfo};
+    use crate::SECONDS_PER_YEAR;
+
+    const HUB: &str = "hub";
+    const BLUNA: &str = "bluna";
+    const FACTORY: &str = "factory";
+    const ORACLE: &str = "oracle";
+    // A contract whose info can't be queried
+    const UNREACHABLE: &str = "unreachable";
+
+    // A staking hub's `state {}` response, with more fields than we read
+    #[derive(Serialize)]
+    struct MockHubState {
+        exchange_rate: Decimal,
+        bluna_exchange_rate: Decimal,
+        total_bond_amount: Uint128,
+        last_index_modification: u64,
+    }
+
+    // Answers the queries a wallet makes of the chain and other contracts: a
+    // staking hub and the bLuna token, an oracle pricing at 0.8, a pair
+    // factory and its pairs, and Terra contract info, which fails for
+    // `UNREACHABLE`, with bank queries as usual
+    struct WalletQuerier {
+        base: MockQuerier,
+        exchange_rate: Decimal,
+        bluna_balance: Uint128,
+        code_ids: Vec<(&'static str, u64)>,
+        // Pairs answering `pair {}`, of which the factory registered `registered`
+        pairs: Vec<PairInfo>,
+        registered: Vec<&'static str>,
+        // Admin of every contract in `code_ids`
+        admin: Option<&'static str>,
+    }
+
+    impl Querier for WalletQuerier {
+        fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+            let request: QueryRequest<TerraQueryWrapper> = match from_slice(bin_request) {
+                Ok(request) => request,
+                Err(e) => return SystemResult::Err(SystemError::InvalidRequest {
+                    error: e.to_string(),
+                    request: bin_request.into(),
+                }),
+            };
+            match &request {
+                QueryRequest::Wasm(WasmQuery::Smart { contract_addr, .. }) if contract_addr == HUB => {
+                    SystemResult::Ok(to_binary(&MockHubState {
+                        exchange_rate: self.exchange_rate,
+                        bluna_exchange_rate: Decimal::percent(90),
+                        total_bond_amount: Uint128::new(1_000_000),
+                        last_index_modification: 12345,
+                    }).into())
+                },
+                QueryRequest::Wasm(WasmQuery::Smart { contract_addr, .. }) if contract_addr == ORACLE => {
+                    SystemResult::Ok(to_binary(&PriceResponse { price: Decimal::percent(80) }).into())
+                },
+                QueryRequest::Wasm(WasmQuery::Smart { contract_addr, .. }) if contract_addr == BLUNA => {
+                    SystemResult::Ok(to_binary(&BalanceResponse { balance: self.bluna_balance }).into())
+                },
+                QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg }) if contract_addr == FACTORY => {
+                    let FactoryQueryMsg::Pair { asset_infos } = from_binary(msg).unwrap();
+                    let pair = self.pairs.iter()
+                        .find(|pair| pair.asset_infos == asset_infos && self.registered.contains(&pair.contract_addr.as_str()));
+                    match pair {
+                        Some(pair) => SystemResult::Ok(to_binary(pair).into()),
+                        None => SystemResult::Ok(ContractResult::Err("Pair not found".to_string())),
+                    }
+                },
+                QueryRequest::Wasm(WasmQuery::Smart { contract_addr, .. }) if self.pairs.iter().any(|pair| &pair.contract_addr == contract_addr) => {
+                    let pair = self.pairs.iter().find(|pair| &pair.contract_addr == contract_addr).unwrap();
+                    SystemResult::Ok(to_binary(pair).into())
+                },
+                QueryRequest::Custom(TerraQueryWrapper { query_data: TerraQuery::ContractInfo { contract_address }, .. }) if contract_address == UNREACHABLE => {
+                    SystemResult::Ok(ContractResult::Err("query failed".to_string()))
+                },
+                QueryRequest::Custom(TerraQueryWrapper { query_data: TerraQuery::ContractInfo { contract_address }, .. }) => {
+                    match self.code_ids.iter().find(|(address, _)| address == contract_address) {
+                        Some((address, code_id)) => SystemResult::Ok(to_binary(&ContractInfoResponse {
+                            address: address.to_string(),
+                            creator: "creator".to_string(),
+                            code_id: *code_id,
+                            admin: self.admin.map(String::from),
+                        }).into()),
+                        None => SystemResult::Err(SystemError::NoSuchContract { addr: contract_address.clone() }),
+                    }
+                },
+                _ => self.base.raw_query(bin_request),
+            }
+        }
+    }
+
+    // The wallet holds `uluna_balance` and `bluna_balance`, and the hub
+    // reports `exchange_rate`
+    fn mock_dependencies(
+        uluna_balance: u128,
+        bluna_balance: u128,
+        exchange_rate: Decimal,
+    ) -> OwnedDeps<MockStorage, MockApi, WalletQuerier> {
+        OwnedDeps {
+            storage: MockStorage::default(),
+            api: MockApi::default(),
+            querier: WalletQuerier {
+                base: MockQuerier::new(&[(MOCK_CONTRACT_ADDR, &coins(uluna_balance, "uluna"))]),
+                exchange_rate,
+                bluna_balance: Uint128::new(bluna_balance),
+                code_ids: vec![],
+                pairs: vec![],
+                registered: vec![],
+                admin: None,
+            },
+        }
+    }
+
+    fn hub_source(rate_field: Option<HubRateField>) -> PriceSource {
+        PriceSource::Hub {
+            contract: Addr::unchecked(HUB),
+            query: None,
+            rate_field,
+        }
+    }
+
+    // Instantiate a wallet holding uluna and bluna priced by the hub, and deposit `deposit` uluna
+    fn setup_bluna_wallet(deps: &mut OwnedDeps<MockStorage, MockApi, WalletQuerier>, deposit: u128) {
+        let bluna = Asset::Token(Addr::unchecked(BLUNA));
+
+        instantiate(deps.as_mut(), mock_env(), mock_info("trader", &[]), InstantiateMsg {
+            funder: Addr::unchecked("funder"),
+            base_asset: Asset::Native("uluna".to_string()),
+            trader_withdrawal_address: None,
+            whitelist: None,
+            assets: Some(vec![Asset::Native("uluna".to_string()), bluna.clone()]),
+            commission: None,
+            min_commission: None,
+            max_commission: None,
+            commission_tiers: None,
+            hurdle_rate: None,
+            management_fee: None,
+            pooled: None,
+            share_token: None,
+            price_sources: Some(vec![AssetPrice { asset: bluna, source: hub_source(None) }]),
+        }).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &coins(deposit, "uluna")), ExecuteMsg::Deposit {}).unwrap();
+    }
+
+    // Forward a swap of 100 uluna to a whitelisted pair, snapshotting the wallet's balances
+    fn forward_trade(
+        deps: &mut OwnedDeps<MockStorage, MockApi, WalletQuerier>,
+        min_nav_after: Option<u128>,
+        max_loss_bps: Option<u16>,
+    ) -> Result<Response, ContractError> {
+        let _ = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::AddWhitelist { address: Addr::unchecked("pair"), allowed_msgs: None });
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::SendNative {
+            address: Addr::unchecked("pair"),
+            funds: Some(coins(100, "uluna")),
+            msg: Some(Binary::from(br#"{"swap":{}}"#.as_ref())),
+            min_nav_after: min_nav_after.map(Uint128::new),
+            max_loss_bps,
+        })
+    }
+
+    fn trade_reply(events: Vec<Event>) -> Reply {
+        Reply {
+            id: TRADE_REPLY_ID,
+            result: ContractResult::Ok(SubMsgExecutionResponse { events, data: None }),
+        }
+    }
+
+    #[test]
+    fn hub_price_reads_requested_rate() {
+        let deps = mock_dependencies(0, 0, Decimal::percent(120));
+        let querier = deps.as_ref().querier;
+
+        assert_eq!(query_price(&querier, &hub_source(None)).unwrap(), Decimal::percent(120));
+        assert_eq!(
+            query_price(&querier, &hub_source(Some(HubRateField::BlunaExchangeRate))).unwrap(),
+            Decimal::percent(90),
+        );
+        assert!(query_price(&querier, &hub_source(Some(HubRateField::StlunaExchangeRate))).is_err());
+    }
+
+    #[test]
+    fn withdraw_values_tokens_at_hub_rate() {
+        let mut deps = mock_dependencies(500, 500, Decimal::percent(120));
+        setup_bluna_wallet(&mut deps, 1000);
+
+        // 500 uluna + 500 bluna at 1.2 is 1100, so the trader earns 20% of 100
+        let res = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), ExecuteMsg::Withdraw { amount: None, split: None, preference: None, min_out: None }).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(20, "uluna") }),
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: BLUNA.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer { recipient: "funder".to_string(), amount: Uint128::new(500) }).unwrap(),
+                funds: vec![],
+            }),
+            SubMsg::new(BankMsg::Send { to_address: "funder".to_string(), amount: coins(480, "uluna") }),
+        ]);
+    }
+
+    #[test]
+    fn fixed_and_oracle_prices_are_queried() {
+        let deps = mock_dependencies(0, 0, Decimal::one());
+        let querier = deps.as_ref().querier;
+
+        assert_eq!(query_price(&querier, &PriceSource::Fixed(Decimal::percent(150))).unwrap(), Decimal::percent(150));
+        let oracle = PriceSource::Oracle { contract: Addr::unchecked(ORACLE), query: to_binary(&"price").unwrap() };
+        assert_eq!(query_price(&querier, &oracle).unwrap(), Decimal::percent(80));
+    }
+
+    #[test]
+    fn failing_price_source_is_replaced_while_locked() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+        let bluna = Asset::Token(Addr::unchecked(BLUNA));
+        let replace = |source| ExecuteMsg::ReplacePriceSource { asset: bluna.clone(), source };
+        let fixed = PriceSource::Fixed(Decimal::one());
+        let withdraw = ExecuteMsg::Withdraw { amount: None, split: None, preference: None, min_out: None };
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), replace(fixed.clone())).unwrap_err();
+        assert!(matches!(err, ContractError::PriceSourceAnswers { .. }));
+
+        // The hub is removed while the trader has locked the wallet
+        let mut state = STATE.load(deps.as_ref().storage).unwrap();
+        state.price_sources[0].source = PriceSource::Hub { contract: Addr::unchecked("removed"), query: None, rate_field: None };
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::ToggleLock {}).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), withdraw.clone()).unwrap_err();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("stranger", &[]), replace(fixed.clone())).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let broken = PriceSource::Oracle { contract: Addr::unchecked("removed"), query: to_binary(&"price").unwrap() };
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), replace(broken)).unwrap_err();
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), replace(fixed)).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), withdraw).unwrap();
+    }
+
+    #[test]
+    fn every_asset_needs_a_price_source() {
+        let mut deps = mock_dependencies(0, 0, Decimal::one());
+        let bluna = Asset::Token(Addr::unchecked(BLUNA));
+        let msg = |price_sources| InstantiateMsg {
+            funder: Addr::unchecked("funder"),
+            base_asset: Asset::Native("uluna".to_string()),
+            trader_withdrawal_address: None,
+            whitelist: None,
+            assets: Some(vec![bluna.clone()]),
+            commission: None,
+            min_commission: None,
+            max_commission: None,
+            commission_tiers: None,
+            hurdle_rate: None,
+            management_fee: None,
+            pooled: None,
+            share_token: None,
+            price_sources,
+        };
+
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("trader", &[]), msg(None)).unwrap_err();
+        assert!(matches!(err, ContractError::MissingPriceSource { asset } if asset == BLUNA));
+
+        // A one to one peg is set explicitly
+        let peg = AssetPrice { asset: bluna.clone(), source: PriceSource::Fixed(Decimal::one()) };
+        instantiate(deps.as_mut(), mock_env(), mock_info("trader", &[]), msg(Some(vec![peg]))).unwrap();
+
+        let update = ExecuteMsg::UpdateState {
+            assets: None,
+            commission: None,
+            commission_tiers: None,
+            user: None,
+            price_sources: Some(vec![]),
+            hurdle_rate: None,
+            management_fee: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), update).unwrap_err();
+        assert!(matches!(err, ContractError::MissingPriceSource { .. }));
+    }
+
+    #[test]
+    fn update_state_keeps_the_base_asset() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+        let bluna = Asset::Token(Addr::unchecked(BLUNA));
+
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::UpdateState {
+            assets: Some(vec![bluna.clone()]),
+            commission: None,
+            commission_tiers: None,
+            user: None,
+            price_sources: None,
+            hurdle_rate: None,
+            management_fee: None,
+        }).unwrap();
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        assert_eq!(state.assets, vec![Asset::Native("uluna".to_string()), bluna]);
+    }
+
+    #[test]
+    fn withdraw_values_tokens_at_oracle_price() {
+        let mut deps = mock_dependencies(500, 1000, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+        let oracle = PriceSource::Oracle { contract: Addr::unchecked(ORACLE), query: to_binary(&"price").unwrap() };
+        let mut state = STATE.load(deps.as_ref().storage).unwrap();
+        state.price_sources[0].source = oracle;
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        // 500 uluna + 1000 bluna at 0.8 is 1300, so the trader earns 20% of 300
+        let res = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), ExecuteMsg::Withdraw { amount: None, split: None, preference: None, min_out: None }).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(60, "uluna") }),
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: BLUNA.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer { recipient: "funder".to_string(), amount: Uint128::new(1000) }).unwrap(),
+                funds: vec![],
+            }),
+            SubMsg::new(BankMsg::Send { to_address: "funder".to_string(), amount: coins(440, "uluna") }),
+        ]);
+    }
+
+    #[test]
+    fn deposit_cw20_credits_listed_tokens_at_their_value() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::percent(120));
+        setup_bluna_wallet(&mut deps, 1000);
+        let deposit = |sender: &str| ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: sender.to_string(),
+            amount: Uint128::new(100),
+            msg: to_binary(&ReceiveMsg::Deposit {}).unwrap(),
+        });
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("unlisted", &[]), deposit("funder")).unwrap_err();
+        assert!(matches!(err, ContractError::UnlistedToken {}));
+        let err = execute(deps.as_mut(), mock_env(), mock_info(BLUNA, &[]), deposit("stranger")).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        // 100 bluna at 1.2 is worth 120
+        deps.querier.bluna_balance = Uint128::new(100);
+        let res = execute(deps.as_mut(), mock_env(), mock_info(BLUNA, &[]), deposit("funder")).unwrap();
+        assert!(res.attributes.contains(&attr("value", "120")));
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        assert_eq!(state.base_investment, Uint128::new(1120));
+        assert_eq!(state.high_water_mark, Uint128::new(1120));
+    }
+
+    #[test]
+    fn withdraw_pro_rata_pays_share_of_every_asset() {
+        let mut deps = mock_dependencies(500, 500, Decimal::percent(120));
+        setup_bluna_wallet(&mut deps, 1000);
+
+        // The trader's 20 of 1100 and the funder's 1080 of 1100 come from both assets alike
+        let msg = ExecuteMsg::Withdraw { amount: None, split: Some(WithdrawSplit::ProRata), preference: None, min_out: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: BLUNA.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer { recipient: "trader".to_string(), amount: Uint128::new(9) }).unwrap(),
+                funds: vec![],
+            }),
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(9, "uluna") }),
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: BLUNA.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer { recipient: "funder".to_string(), amount: Uint128::new(490) }).unwrap(),
+                funds: vec![],
+            }),
+            SubMsg::new(BankMsg::Send { to_address: "funder".to_string(), amount: coins(490, "uluna") }),
+        ]);
+    }
+
+    #[test]
+    fn withdraw_checks_minimum_out() {
+        let mut deps = mock_dependencies(500, 500, Decimal::percent(120));
+        setup_bluna_wallet(&mut deps, 1000);
+        let bluna = Asset::Token(Addr::unchecked(BLUNA));
+
+        let msg = ExecuteMsg::Withdraw {
+            amount: Some(Uint128::new(600)),
+            split: None,
+            preference: Some(vec![bluna.clone()]),
+            min_out: Some(vec![AssetAmount { asset: bluna.clone(), amount: Uint128::new(501) }]),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::MinimumOutNotMet { .. }));
+
+        // Preferring bluna pays the funder's 600 entirely in bluna
+        let msg = ExecuteMsg::Withdraw {
+            amount: Some(Uint128::new(600)),
+            split: None,
+            preference: Some(vec![bluna.clone()]),
+            min_out: Some(vec![AssetAmount { asset: bluna, amount: Uint128::new(500) }]),
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(20, "uluna") }),
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: BLUNA.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer { recipient: "funder".to_string(), amount: Uint128::new(500) }).unwrap(),
+                funds: vec![],
+            }),
+        ]);
+    }
+
+    #[test]
+    fn withdraw_carries_losses_forward() {
+        // 500 uluna + 250 bluna at 1.2 is 800, down 200 on the deposit
+        let mut deps = mock_dependencies(500, 250, Decimal::percent(120));
+        setup_bluna_wallet(&mut deps, 1000);
+
+        let msg = ExecuteMsg::Withdraw { amount: Some(Uint128::new(400)), split: None, preference: None, min_out: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "funder".to_string(), amount: coins(400, "uluna") }),
+        ]);
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        assert_eq!(state.base_investment, Uint128::new(500));
+        assert_eq!(state.high_water_mark, Uint128::new(500));
+
+        // Back up to 550, of which 100 recovers the carried loss and 50 is profit
+        deps.querier.base.update_balance(MOCK_CONTRACT_ADDR, coins(550, "uluna"));
+        deps.querier.bluna_balance = Uint128::zero();
+        let msg = ExecuteMsg::Withdraw { amount: None, split: None, preference: None, min_out: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(10, "uluna") }),
+            SubMsg::new(BankMsg::Send { to_address: "funder".to_string(), amount: coins(540, "uluna") }),
+        ]);
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        assert_eq!(state.high_water_mark, Uint128::zero());
+    }
+
+    #[test]
+    fn withdraw_pays_commission_once_per_gain() {
+        let mut deps = mock_dependencies(1100, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+
+        // The trader takes 20 of the 100 gained, leaving the mark at 1080
+        let msg = ExecuteMsg::Withdraw { amount: Some(Uint128::zero()), split: None, preference: None, min_out: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), msg.clone()).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(20, "uluna") }),
+        ]);
+        assert_eq!(STATE.load(deps.as_ref().storage).unwrap().high_water_mark, Uint128::new(1080));
+
+        // Falling and climbing back to the mark earns nothing more
+        deps.querier.base.update_balance(MOCK_CONTRACT_ADDR, coins(1080, "uluna"));
+        let res = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), msg).unwrap();
+        assert!(res.messages.is_empty());
+    }
+
+    #[test]
+    fn withdraw_pays_commission_above_hurdle() {
+        let mut deps = mock_dependencies(1150, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+
+        let msg = ExecuteMsg::UpdateState {
+            assets: None,
+            commission: None,
+            commission_tiers: None,
+            user: None,
+            price_sources: None,
+            hurdle_rate: Some(Decimal::percent(10)),
+            management_fee: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), msg).unwrap();
+
+        // A year at 10% puts the hurdle at 1100, leaving 50 of profit
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let msg = ExecuteMsg::Withdraw { amount: Some(Uint128::zero()), split: None, preference: None, min_out: None };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("trader", &[]), msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(10, "uluna") }),
+        ]);
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        assert_eq!(state.high_water_mark, Uint128::new(1140));
+        assert_eq!(state.last_crystallization, env.block.time.seconds());
+    }
+
+    #[test]
+    fn management_fee_accrues_and_is_claimed() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+
+        let msg = ExecuteMsg::UpdateState {
+            assets: None,
+            commission: None,
+            commission_tiers: None,
+            user: None,
+            price_sources: None,
+            hurdle_rate: None,
+            management_fee: Some(Decimal::percent(2)),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), msg).unwrap();
+
+        // A year at 2% of 1000
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let res: ManagementFeeResponse = from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::GetManagementFee {}).unwrap()).unwrap();
+        assert_eq!(res.accrued_fee, Uint128::new(20));
+
+        execute(deps.as_mut(), env.clone(), mock_info("funder", &[]), ExecuteMsg::ClaimManagementFee {}).unwrap_err();
+        let res = execute(deps.as_mut(), env.clone(), mock_info("trader", &[]), ExecuteMsg::ClaimManagementFee {}).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(20, "uluna") }),
+        ]);
+        let res: ManagementFeeResponse = from_binary(&query(deps.as_ref(), env, QueryMsg::GetManagementFee {}).unwrap()).unwrap();
+        assert_eq!(res.accrued_fee, Uint128::zero());
+    }
+
+    #[test]
+    fn withdraw_applies_commission_tiers() {
+        let mut deps = mock_dependencies(1150, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+
+        let update = |tiers| ExecuteMsg::UpdateState {
+            assets: None,
+            commission: None,
+            commission_tiers: Some(tiers),
+            user: None,
+            price_sources: None,
+            hurdle_rate: None,
+            management_fee: None,
+        };
+        let unordered = vec![
+            CommissionTier { threshold: Decimal::percent(20), commission: Decimal::percent(40) },
+            CommissionTier { threshold: Decimal::percent(10), commission: Decimal::percent(30) },
+        ];
+        let err = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), update(unordered)).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidCommissionTiers {}));
+        let tiers = vec![CommissionTier { threshold: Decimal::percent(10), commission: Decimal::percent(30) }];
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), update(tiers)).unwrap();
+
+        // 20% of the first 100 and 30% of the 50 above it
+        let msg = ExecuteMsg::Withdraw { amount: Some(Uint128::zero()), split: None, preference: None, min_out: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(35, "uluna") }),
+        ]);
+    }
+
+    #[test]
+    fn update_rejects_commission_out_of_range() {
+        let mut deps = mock_dependencies(0, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+
+        let update = |commission| ExecuteMsg::UpdateState {
+            assets: None,
+            commission: Some(commission),
+            commission_tiers: None,
+            user: None,
+            price_sources: None,
+            hurdle_rate: None,
+            management_fee: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), update(Decimal::permille(195))).unwrap_err();
+        assert!(matches!(err, ContractError::CommissionOutOfRange { .. }));
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), update(Decimal::permille(205))).unwrap();
+        assert_eq!(STATE.load(deps.as_ref().storage).unwrap().commission, Decimal::permille(205));
+    }
+
+    #[test]
+    fn claim_commission_leaves_funder_invested() {
+        let mut deps = mock_dependencies(1100, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), ExecuteMsg::ClaimCommission {}).unwrap_err();
+        let res = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::ClaimCommission {}).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(20, "uluna") }),
+        ]);
+
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        assert_eq!(state.base_investment, Uint128::new(1000));
+        assert_eq!(state.high_water_mark, Uint128::new(1080));
+    }
+
+    #[test]
+    fn withdraw_splits_principal_and_profit() {
+        let mut deps = mock_dependencies(1100, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+
+        // After 20 of commission, half of the remaining 1080 is withdrawn
+        let msg = ExecuteMsg::Withdraw { amount: Some(Uint128::new(540)), split: None, preference: None, min_out: None };
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), msg).unwrap();
+
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        assert_eq!(state.base_investment, Uint128::new(500));
+        assert_eq!(state.realized_profit, Uint128::new(40));
+        assert_eq!(state.unrealized_profit, Uint128::new(40));
+        assert_eq!(state.high_water_mark, Uint128::new(540));
+    }
+
+    #[test]
+    fn migrate_from_legacy_state() {
+        let mut deps = mock_dependencies(0, 0, Decimal::one());
+        deps.querier.code_ids = vec![("terraswap", 3)];
+        set_contract_version(deps.as_mut().storage, LEGACY_CONTRACT_NAME, "0.1.0").unwrap();
+        deps.as_mut().storage.set(b"state", br#"{"trader":"trader","trader_withdrawal_address":"trader","funder":"funder","assets":[{"Native":"uluna"},{"Token":"bluna"}],"whitelist":["terraswap"],"trader_lock":false,"funder_lock":false,"base_investment":"1000","commission":20}"#);
+
+        migrate(deps.as_mut(), mock_env(), MigrateMsg { code_id: 2 }).unwrap();
+
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        assert_eq!(state.base_asset, Asset::Native("uluna".to_string()));
+        assert_eq!(state.base_investment, Uint128::new(1000));
+        assert_eq!(state.high_water_mark, Uint128::new(1000));
+        assert_eq!(state.commission, Decimal::percent(20));
+        assert_eq!(state.min_commission, Decimal::percent(20));
+        // Assets were counted one to one before they could be priced
+        assert_eq!(state.price_sources, vec![AssetPrice {
+            asset: Asset::Token(Addr::unchecked(BLUNA)),
+            source: PriceSource::Fixed(Decimal::one()),
+        }]);
+        // Carried over entries are pinned to the code they run
+        assert_eq!(WHITELIST.load(deps.as_ref().storage, &Addr::unchecked("terraswap")).unwrap().code_id, Some(3));
+        let version = get_contract_version(deps.as_ref().storage).unwrap();
+        assert_eq!(version.contract, CONTRACT_NAME);
+        assert_eq!(version.version, CONTRACT_VERSION);
+    }
+
+    #[test]
+    fn migrate_refuses_downgrade() {
+        let mut deps = mock_dependencies(0, 0, Decimal::one());
+        set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "99.0.0").unwrap();
+
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { code_id: 2 }).unwrap_err();
+        assert!(matches!(err, ContractError::CannotDowngrade { .. }));
+    }
+
+    #[test]
+    fn migrate_requires_approved_proposal() {
+        let mut deps = mock_dependencies(0, 0, Decimal::one());
+        instantiate(deps.as_mut(), mock_env(), mock_info("trader", &[]), InstantiateMsg {
+            funder: Addr::unchecked("funder"),
+            base_asset: Asset::Native("uluna".to_string()),
+            trader_withdrawal_address: None,
+            whitelist: None,
+            assets: None,
+            commission: None,
+            min_commission: None,
+            max_commission: None,
+            commission_tiers: None,
+            hurdle_rate: None,
+            management_fee: None,
+            pooled: None,
+            share_token: None,
+            price_sources: None,
+        }).unwrap();
+
+        let propose = ExecuteMsg::ProposeMigration { code_id: 2 };
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), propose.clone()).unwrap_err();
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), propose).unwrap();
+
+        // Not yet approved by the funder
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { code_id: 2 }).unwrap_err();
+        assert!(matches!(err, ContractError::MigrationNotApproved { code_id: 2 }));
+
+        let approve = ExecuteMsg::ApproveMigration { code_id: 2 };
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), approve).unwrap();
+
+        // Approved, but for a different code ID
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { code_id: 3 }).unwrap_err();
+        assert!(matches!(err, ContractError::MigrationNotApproved { code_id: 3 }));
+
+        // Approved, but expired
+        let mut env = mock_env();
+        env.block.height += MIGRATION_PROPOSAL_BLOCKS;
+        let err = migrate(deps.as_mut(), env, MigrateMsg { code_id: 2 }).unwrap_err();
+        assert!(matches!(err, ContractError::MigrationProposalExpired {}));
+
+        migrate(deps.as_mut(), mock_env(), MigrateMsg { code_id: 2 }).unwrap();
+        assert_eq!(MIGRATION_PROPOSAL.may_load(deps.as_ref().storage).unwrap(), None);
+    }
+
+    #[test]
+    fn wallet_migrates_itself_once_approved() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+        deps.querier.code_ids = vec![(MOCK_CONTRACT_ADDR, 1)];
+        let migrate_self = ExecuteMsg::ExecuteMigration {};
+
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::ProposeMigration { code_id: 2 }).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), migrate_self.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::NoApprovedMigration {}));
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), ExecuteMsg::ApproveMigration { code_id: 2 }).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("stranger", &[]), migrate_self.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        // Someone else could migrate the wallet without either party
+        let err = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), migrate_self.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::NotOwnAdmin {}));
+        deps.querier.admin = Some(MOCK_CONTRACT_ADDR);
+
+        let mut env = mock_env();
+        env.block.height += MIGRATION_PROPOSAL_BLOCKS;
+        let err = execute(deps.as_mut(), env, mock_info("funder", &[]), migrate_self.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::MigrationProposalExpired {}));
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), migrate_self).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(WasmMsg::Migrate {
+            contract_addr: MOCK_CONTRACT_ADDR.to_string(),
+            new_code_id: 2,
+            msg: to_binary(&MigrateMsg { code_id: 2 }).unwrap(),
+        })]);
+    }
+
+    #[test]
+    fn pooled_funders_redeem_shares_at_nav() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        instantiate(deps.as_mut(), mock_env(), mock_info("trader", &[]), InstantiateMsg {
+            funder: Addr::unchecked("alice"),
+            base_asset: Asset::Native("uluna".to_string()),
+            trader_withdrawal_address: None,
+            whitelist: None,
+            assets: None,
+            commission: None,
+            min_commission: None,
+            max_commission: None,
+            commission_tiers: None,
+            hurdle_rate: None,
+            management_fee: None,
+            pooled: Some(true),
+            share_token: None,
+            price_sources: None,
+        }).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("alice", &coins(1000, "uluna")), ExecuteMsg::Deposit {}).unwrap();
+
+        // The wallet doubles, so bob's deposit buys half as many shares
+        deps.querier.base.update_balance(MOCK_CONTRACT_ADDR, coins(3000, "uluna"));
+        execute(deps.as_mut(), mock_env(), mock_info("bob", &coins(1000, "uluna")), ExecuteMsg::Deposit {}).unwrap();
+
+        let list = |deps: &OwnedDeps<MockStorage, MockApi, WalletQuerier>, start_after| -> FundersResponse {
+            from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::ListFunders { start_after, limit: None }).unwrap()).unwrap()
+        };
+        assert_eq!(list(&deps, None).funders, vec![
+            FunderResponse { address: Addr::unchecked("alice"), shares: Uint128::new(1000), cost_basis: Uint128::new(1000) },
+            FunderResponse { address: Addr::unchecked("bob"), shares: Uint128::new(500), cost_basis: Uint128::new(1000) },
+        ]);
+        assert_eq!(list(&deps, Some(Addr::unchecked("alice"))).funders.len(), 1);
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), ExecuteMsg::Withdraw { amount: None, split: None, preference: None, min_out: None }).unwrap_err();
+        assert!(matches!(err, ContractError::Pooled {}));
+
+        // Alice's shares are worth 2000, and commission is owed on her 1000 gain
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), ExecuteMsg::Redeem { shares: None, split: None }).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(200, "uluna") }),
+            SubMsg::new(BankMsg::Send { to_address: "alice".to_string(), amount: coins(1800, "uluna") }),
+        ]);
+        assert_eq!(list(&deps, None).funders.len(), 1);
+        assert_eq!(STATE.load(deps.as_ref().storage).unwrap().total_shares, Uint128::new(500));
+
+        // Bob's shares are still out, but the wallet has lost everything
+        deps.querier.base.update_balance(MOCK_CONTRACT_ADDR, coins(100, "uluna"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("carol", &coins(100, "uluna")), ExecuteMsg::Deposit {}).unwrap_err();
+        assert!(matches!(err, ContractError::ZeroNav {}));
+
+        // A share is worth 4000, so carol's 1000 would buy none
+        deps.querier.base.update_balance(MOCK_CONTRACT_ADDR, coins(2_001_000, "uluna"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("carol", &coins(1000, "uluna")), ExecuteMsg::Deposit {}).unwrap_err();
+        assert!(matches!(err, ContractError::ZeroShares {}));
+        assert_eq!(list(&deps, None).funders.len(), 1);
+    }
+
+    // Instantiate a pooled wallet in uluna holding its shares as the "shares" token
+    fn setup_share_token_wallet(deps: &mut OwnedDeps<MockStorage, MockApi, WalletQuerier>) {
+        let res = instantiate(deps.as_mut(), mock_env(), mock_info("trader", &[]), InstantiateMsg {
+            funder: Addr::unchecked("alice"),
+            base_asset: Asset::Native("uluna".to_string()),
+            trader_withdrawal_address: None,
+            whitelist: None,
+            assets: None,
+            commission: None,
+            min_commission: None,
+            max_commission: None,
+            commission_tiers: None,
+            hurdle_rate: None,
+            management_fee: None,
+            pooled: None,
+            share_token: Some(ShareTokenConfig { code_id: 7, name: "Wallet Shares".to_string(), symbol: "SHARE".to_string() }),
+            price_sources: None,
+        }).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.messages[0].id, SHARE_TOKEN_REPLY_ID);
+
+        reply(deps.as_mut(), mock_env(), Reply {
+            id: SHARE_TOKEN_REPLY_ID,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![Event::new("instantiate_contract").add_attribute("contract_address", "shares")],
+                data: None,
+            }),
+        }).unwrap();
+    }
+
+    #[test]
+    fn share_token_holders_redeem_at_nav() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_share_token_wallet(&mut deps);
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        assert!(state.pooled);
+        assert_eq!(state.share_token, Some(Addr::unchecked("shares")));
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &coins(1000, "uluna")), ExecuteMsg::Deposit {}).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(WasmMsg::Execute {
+            contract_addr: "shares".to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Mint { recipient: "alice".to_string(), amount: Uint128::new(1000) }).unwrap(),
+            funds: vec![],
+        })]);
+
+        // Shares can only be redeemed as tokens, and only the share token is accepted
+        let err = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), ExecuteMsg::Redeem { shares: None, split: None }).unwrap_err();
+        assert!(matches!(err, ContractError::RedeemShareToken {}));
+        let redeem = |amount| ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "carol".to_string(),
+            amount: Uint128::new(amount),
+            msg: to_binary(&ReceiveMsg::Redeem { split: None }).unwrap(),
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info(BLUNA, &[]), redeem(500)).unwrap_err();
+        assert!(matches!(err, ContractError::UnlistedToken {}));
+
+        // Carol was transferred half the shares. Commission is crystallized
+        // on the whole pool's 500 gain, and her shares redeem net of it.
+        deps.querier.base.update_balance(MOCK_CONTRACT_ADDR, coins(1500, "uluna"));
+        let res = execute(deps.as_mut(), mock_env(), mock_info("shares", &[]), redeem(500)).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(100, "uluna") }),
+            SubMsg::new(BankMsg::Send { to_address: "carol".to_string(), amount: coins(700, "uluna") }),
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: "shares".to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Burn { amount: Uint128::new(500) }).unwrap(),
+                funds: vec![],
+            }),
+        ]);
+        assert_eq!(STATE.load(deps.as_ref().storage).unwrap().total_shares, Uint128::new(500));
+    }
+
+    #[test]
+    fn share_token_commission_follows_the_pool() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_share_token_wallet(&mut deps);
+        let mint = |recipient: &str, amount| SubMsg::new(WasmMsg::Execute {
+            contract_addr: "shares".to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Mint { recipient: recipient.to_string(), amount: Uint128::new(amount) }).unwrap(),
+            funds: vec![],
+        });
+        execute(deps.as_mut(), mock_env(), mock_info("alice", &coins(1000, "uluna")), ExecuteMsg::Deposit {}).unwrap();
+
+        // The wallet doubles before bob buys in, so commission on alice's
+        // gain is paid before he buys at what is left
+        deps.querier.base.update_balance(MOCK_CONTRACT_ADDR, coins(4000, "uluna"));
+        let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &coins(2000, "uluna")), ExecuteMsg::Deposit {}).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "trader".to_string(), amount: coins(200, "uluna") }),
+            mint("bob", 1111),
+        ]);
+
+        // Alice transfers her shares to bob, who redeems them without
+        // escaping the commission already paid on them
+        deps.querier.base.update_balance(MOCK_CONTRACT_ADDR, coins(3800, "uluna"));
+        let res = execute(deps.as_mut(), mock_env(), mock_info("shares", &[]), ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "bob".to_string(),
+            amount: Uint128::new(1000),
+            msg: to_binary(&ReceiveMsg::Redeem { split: None }).unwrap(),
+        })).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "bob".to_string(), amount: coins(1800, "uluna") }),
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: "shares".to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Burn { amount: Uint128::new(1000) }).unwrap(),
+                funds: vec![],
+            }),
+        ]);
+
+        // Token holders aren't tracked by the wallet
+        let funders: FundersResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::ListFunders { start_after: None, limit: None }).unwrap()).unwrap();
+        assert!(funders.funders.is_empty());
+    }
+
+    #[test]
+    fn whitelist_is_added_removed_and_listed() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+
+        for address in ["pair_c", "pair_a", "pair_b"] {
+            let msg = ExecuteMsg::AddWhitelist { address: Addr::unchecked(address), allowed_msgs: None };
+            execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), msg).unwrap();
+        }
+        let err = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), ExecuteMsg::AddWhitelist { address: Addr::unchecked("pair_d"), allowed_msgs: None }).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        // The funder's lock stops the trader changing the whitelist
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), ExecuteMsg::ToggleLock {}).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::RemoveWhitelist { address: Addr::unchecked("pair_b") }).unwrap_err();
+        assert!(matches!(err, ContractError::Locked {}));
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), ExecuteMsg::ToggleLock {}).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::RemoveWhitelist { address: Addr::unchecked("pair_b") }).unwrap();
+
+        let list = |start_after: Option<&str>, limit| -> Vec<Addr> {
+            let msg = QueryMsg::ListWhitelist { start_after: start_after.map(Addr::unchecked), limit };
+            let res: WhitelistResponse = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+            res.entries.into_iter().map(|entry| entry.address).collect()
+        };
+        assert_eq!(list(None, None), vec![Addr::unchecked("pair_a"), Addr::unchecked("pair_c")]);
+        assert_eq!(list(None, Some(1)), vec![Addr::unchecked("pair_a")]);
+        assert_eq!(list(Some("pair_a"), None), vec![Addr::unchecked("pair_c")]);
+
+        let send = ExecuteMsg::SendNative { address: Addr::unchecked("pair_b"), funds: None, msg: None, min_nav_after: None, max_loss_bps: None };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send).unwrap_err();
+        assert!(matches!(err, ContractError::NotWhitelisted {}));
+    }
+
+    #[test]
+    fn whitelist_limits_forwarded_messages() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+        let allowed_msgs = Some(vec!["swap".to_string(), "execute_swap_operations".to_string()]);
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::AddWhitelist { address: Addr::unchecked("pair"), allowed_msgs }).unwrap();
+
+        let send = |msg: Option<&str>| ExecuteMsg::SendNative {
+            address: Addr::unchecked("pair"),
+            funds: Some(coins(100, "uluna")),
+            msg: msg.map(|msg| Binary::from(msg.as_bytes())),
+            min_nav_after: None,
+            max_loss_bps: None,
+        };
+        let swap = r#"{"swap":{"offer_asset":{"info":{"native_token":{"denom":"uluna"}},"amount":"100"},"belief_price":null,"max_spread":"0.01","routes":[1,2],"exact":true}}"#;
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send(Some(swap))).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send(Some(r#"{"withdraw_liquidity":{}}"#))).unwrap_err();
+        assert!(matches!(err, ContractError::MessageNotAllowed { msg, .. } if msg == "withdraw_liquidity"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send(Some(r#"{"swap":{},"withdraw_liquidity":{}}"#))).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidPayload {}));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send(None)).unwrap_err();
+        assert!(matches!(err, ContractError::MessageRequired { .. }));
+    }
+
+    #[test]
+    fn forwarded_messages_must_pay_the_wallet() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::AddWhitelist { address: Addr::unchecked("router"), allowed_msgs: None }).unwrap();
+
+        let swap = |to: &str| ExecuteMsg::SendCw20 {
+            address: Addr::unchecked("router"),
+            token_addr: Addr::unchecked(BLUNA),
+            amount: Uint128::new(100),
+            msg: Some(Binary::from(format!(r#"{{"execute_swap_operations":{{"operations":[{{"terra_swap":{{}}}}],"minimum_receive":"99",{}}}}}"#, to).as_bytes())),
+            min_nav_after: None,
+            max_loss_bps: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), swap(&format!(r#""to":"{}""#, MOCK_CONTRACT_ADDR))).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), swap(r#""to":null"#)).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), swap(r#""to":"trader""#)).unwrap_err();
+        assert!(matches!(err, ContractError::ForeignRecipient { recipient } if recipient == "trader"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), swap(r#""nested":[{"receiver":"thief"}]"#)).unwrap_err();
+        assert!(matches!(err, ContractError::ForeignRecipient { recipient } if recipient == "thief"));
+
+        // Messages nested for other contracts are decoded and checked too
+        let nested = |to: &str| format!(r#""msg":"{}""#, Binary::from(format!(r#"{{"swap":{{"to":"{}"}}}}"#, to).as_bytes()).to_base64());
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), swap(&nested(MOCK_CONTRACT_ADDR))).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), swap(&nested("trader"))).unwrap_err();
+        assert!(matches!(err, ContractError::ForeignRecipient { recipient } if recipient == "trader"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), swap(r#""msg":"not base64""#)).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidPayload {}));
+    }
+
+    #[test]
+    fn whitelist_checks_code_ids() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        deps.querier.code_ids = vec![("pair", 5), ("other_pair", 5), ("router", 9)];
+        setup_bluna_wallet(&mut deps, 1000);
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::AddWhitelist { address: Addr::unchecked("pair"), allowed_msgs: None }).unwrap();
+        let entry = WHITELIST.load(deps.as_ref().storage, &Addr::unchecked("pair")).unwrap();
+        assert_eq!(entry.code_id, Some(5));
+
+        let send = |address: &str, msg: &str| ExecuteMsg::SendNative {
+            address: Addr::unchecked(address),
+            funds: Some(coins(100, "uluna")),
+            msg: Some(Binary::from(msg.as_bytes())),
+            min_nav_after: None,
+            max_loss_bps: None,
+        };
+        let swap = r#"{"swap":{}}"#;
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("pair", swap)).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("other_pair", swap)).unwrap_err();
+        assert!(matches!(err, ContractError::NotWhitelisted {}));
+
+        // Any contract running a whitelisted code ID is accepted, for its allowed messages
+        let rule = ExecuteMsg::AddCodeIdWhitelist { code_id: 5, allowed_msgs: Some(vec!["swap".to_string()]) };
+        execute(deps.as_mut(), mock_env(), mock_info("funder", &[]), rule).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("other_pair", swap)).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("other_pair", r#"{"withdraw_liquidity":{}}"#)).unwrap_err();
+        assert!(matches!(err, ContractError::MessageNotAllowed { .. }));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("router", swap)).unwrap_err();
+        assert!(matches!(err, ContractError::NotWhitelisted {}));
+
+        let res: CodeIdWhitelistResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::ListCodeIdWhitelist { start_after: None, limit: None }).unwrap()).unwrap();
+        assert_eq!(res.rules, vec![CodeIdRuleResponse { code_id: 5, allowed_msgs: Some(vec!["swap".to_string()]) }]);
+
+        // The pinned pair is blocked once it is migrated to other code
+        deps.querier.code_ids[0].1 = 6;
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("pair", swap)).unwrap_err();
+        assert!(matches!(err, ContractError::CodeIdChanged { code_id: 5, current: 6, .. }));
+
+        // An address is only left unpinned when it is known not to be a contract
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::AddWhitelist { address: Addr::unchecked("account"), allowed_msgs: None }).unwrap();
+        assert_eq!(WHITELIST.load(deps.as_ref().storage, &Addr::unchecked("account")).unwrap().code_id, None);
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), ExecuteMsg::AddWhitelist { address: Addr::unchecked(UNREACHABLE), allowed_msgs: None }).unwrap_err();
+        assert!(!WHITELIST.has(deps.as_ref().storage, &Addr::unchecked(UNREACHABLE)));
+    }
+
+    #[test]
+    fn whitelist_accepts_factory_pairs_of_listed_assets() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        let pair = |address: &str, asset: AssetInfo| PairInfo {
+            asset_infos: [AssetInfo::NativeToken { denom: "uluna".to_string() }, asset],
+            contract_addr: address.to_string(),
+        };
+        let bluna = || AssetInfo::Token { contract_addr: BLUNA.to_string() };
+        let ust = AssetInfo::NativeToken { denom: "uusd".to_string() };
+        deps.querier.pairs = vec![pair("luna_bluna", bluna()), pair("luna_ust", ust), pair("fake_luna_bluna", bluna())];
+        deps.querier.registered = vec!["luna_bluna", "luna_ust"];
+        setup_bluna_wallet(&mut deps, 1000);
+
+        let send = |address: &str| ExecuteMsg::SendNative {
+            address: Addr::unchecked(address),
+            funds: Some(coins(100, "uluna")),
+            msg: Some(Binary::from(br#"{"swap":{}}"#.as_ref())),
+            min_nav_after: None,
+            max_loss_bps: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("luna_bluna")).unwrap_err();
+        assert!(matches!(err, ContractError::NotWhitelisted {}));
+
+        let rule = ExecuteMsg::AddFactoryWhitelist { factory: Addr::unchecked(FACTORY), allowed_msgs: Some(vec!["swap".to_string()]) };
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), rule).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("luna_bluna")).unwrap();
+
+        // A look-alike the factory didn't register is refused, as is a pair
+        // trading an asset the wallet doesn't hold
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("fake_luna_bluna")).unwrap_err();
+        assert!(matches!(err, ContractError::NotWhitelisted {}));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), send("luna_ust")).unwrap_err();
+        assert!(matches!(err, ContractError::UnlistedAsset { asset } if asset == "uusd"));
+
+        let res: FactoryWhitelistResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::ListFactoryWhitelist { start_after: None, limit: None }).unwrap()).unwrap();
+        assert_eq!(res.rules, vec![FactoryRuleResponse { factory: Addr::unchecked(FACTORY), allowed_msgs: Some(vec!["swap".to_string()]) }]);
+    }
+
+    #[test]
+    fn trade_reply_rejects_unapproved_assets() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+        let wallet = MOCK_CONTRACT_ADDR;
+        let transfer_to_wallet = |token: &str| Event::new("from_contract")
+            .add_attribute("contract_address", "pair")
+            .add_attribute("action", "swap")
+            .add_attribute("contract_address", token)
+            .add_attribute("action", "transfer")
+            .add_attribute("from", "pair")
+            .add_attribute("to", wallet)
+            .add_attribute("amount", "95");
+
+        // Nothing left the approved assets, so nothing else is looked at
+        forward_trade(&mut deps, None, None).unwrap();
+        deps.querier.base.update_balance(wallet, vec![coin(1000, "uluna"), coin(50, "uusd")]);
+        reply(deps.as_mut(), mock_env(), trade_reply(vec![transfer_to_wallet("unlisted")])).unwrap();
+
+        // An unlisted native coin received
+        forward_trade(&mut deps, None, None).unwrap();
+        deps.querier.base.update_balance(wallet, vec![coin(900, "uluna"), coin(100, "uusd")]);
+        let err = reply(deps.as_mut(), mock_env(), trade_reply(vec![])).unwrap_err();
+        assert!(matches!(err, ContractError::UnapprovedAssetReceived { asset } if asset == "uusd"));
+
+        // An unlisted cw20 received, as Terra and wasmd report it
+        deps.querier.base.update_balance(wallet, coins(1000, "uluna"));
+        forward_trade(&mut deps, None, None).unwrap();
+        deps.querier.base.update_balance(wallet, coins(900, "uluna"));
+        let err = reply(deps.as_mut(), mock_env(), trade_reply(vec![transfer_to_wallet("unlisted")])).unwrap_err();
+        assert!(matches!(err, ContractError::UnapprovedAssetReceived { asset } if asset == "unlisted"));
+        deps.querier.base.update_balance(wallet, coins(1000, "uluna"));
+        forward_trade(&mut deps, None, None).unwrap();
+        deps.querier.base.update_balance(wallet, coins(900, "uluna"));
+        let wasm_event = Event::new("wasm")
+            .add_attribute("_contract_address", "unlisted")
+            .add_attribute("action", "send")
+            .add_attribute("from", "pair")
+            .add_attribute("to", wallet);
+        let err = reply(deps.as_mut(), mock_env(), trade_reply(vec![wasm_event])).unwrap_err();
+        assert!(matches!(err, ContractError::UnapprovedAssetReceived { asset } if asset == "unlisted"));
+
+        // A listed token received
+        deps.querier.base.update_balance(wallet, coins(1000, "uluna"));
+        forward_trade(&mut deps, None, None).unwrap();
+        deps.querier.base.update_balance(wallet, coins(900, "uluna"));
+        deps.querier.bluna_balance = Uint128::new(95);
+        reply(deps.as_mut(), mock_env(), trade_reply(vec![transfer_to_wallet(BLUNA)])).unwrap();
+    }
+
+    #[test]
+    fn trade_guard_limits_loss_and_nav() {
+        let mut deps = mock_dependencies(1000, 0, Decimal::one());
+        setup_bluna_wallet(&mut deps, 1000);
+        let wallet = MOCK_CONTRACT_ADDR;
+
+        let err = forward_trade(&mut deps, None, Some(10_001)).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidMaxLoss { max_loss_bps: 10_001 }));
+
+        // A 1% loss passes a 2% limit and fails a 0.5% one
+        forward_trade(&mut deps, None, Some(200)).unwrap();
+        deps.querier.base.update_balance(wallet, coins(990, "uluna"));
+        reply(deps.as_mut(), mock_env(), trade_reply(vec![])).unwrap();
+
+        forward_trade(&mut deps, None, Some(50)).unwrap();
+        deps.querier.base.update_balance(wallet, coins(980, "uluna"));
+        let err = reply(deps.as_mut(), mock_env(), trade_reply(vec![])).unwrap_err();
+        assert!(matches!(err, ContractError::MaxLossExceeded { loss_bps, max_loss_bps: 50 } if loss_bps == Uint128::new(101)));
+
+        deps.querier.base.update_balance(wallet, coins(1000, "uluna"));
+        forward_trade(&mut deps, Some(950), None).unwrap();
+        deps.querier.base.update_balance(wallet, coins(940, "uluna"));
+        let err = reply(deps.as_mut(), mock_env(), trade_reply(vec![])).unwrap_err();
+        assert!(matches!(err, ContractError::NavBelowMinimum { nav, min_nav } if nav == Uint128::new(940) && min_nav == Uint128::new(950)));
+    }
 }
@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{Decimal, StdError, Uint128};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,9 +17,105 @@ pub enum ContractError {
     #[error("Address is not whitelisted.")]
     NotWhitelisted {},
 
+    #[error("{contract} was whitelisted running code ID {code_id}, but now runs {current}.")]
+    CodeIdChanged { contract: String, code_id: u64, current: u64 },
+
+    #[error("Forwarded message must be a JSON object holding a single execute message.")]
+    InvalidPayload {},
+
+    #[error("Message {msg} is not allowed for {contract}.")]
+    MessageNotAllowed { contract: String, msg: String },
+
+    #[error("Forwarded message pays out to {recipient}, not to the wallet.")]
+    ForeignRecipient { recipient: String },
+
+    #[error("Only allowed messages can be sent to {contract}, not a plain transfer.")]
+    MessageRequired { contract: String },
+
     #[error("Minimum Allocation is 15%")]
     MinimumAllocation {},
 
-    #[error("You must send Luna and only Luna with a deposit message.")]
-    NoLunaReceived {},
+    #[error("Commission tier thresholds must be above zero and increasing.")]
+    InvalidCommissionTiers {},
+
+    #[error("Commission of {commission} is outside the bounds of {min} to {max}.")]
+    CommissionOutOfRange { commission: Decimal, min: Decimal, max: Decimal },
+
+    #[error("Commission bounds must satisfy minimum <= maximum <= 1.")]
+    InvalidCommissionBounds {},
+
+    #[error("You must send {asset} and only {asset} with a deposit message.")]
+    InvalidDeposit { asset: String },
+
+    #[error("Token is not in the asset list.")]
+    UnlistedToken {},
+
+    #[error("Asset is not in the asset list: {asset}")]
+    UnlistedAsset { asset: String },
+
+    #[error("Withdrawal pays {amount} {asset}, below the minimum of {min}")]
+    MinimumOutNotMet { asset: String, amount: Uint128, min: Uint128 },
+
+    #[error("Trade returned an asset that is not approved: {asset}")]
+    UnapprovedAssetReceived { asset: String },
+
+    #[error("Trade left {nav} in assets, below the minimum of {min_nav}")]
+    NavBelowMinimum { nav: Uint128, min_nav: Uint128 },
+
+    #[error("Trade lost {loss_bps} bps, above the maximum of {max_loss_bps} bps")]
+    MaxLossExceeded { loss_bps: Uint128, max_loss_bps: u16 },
+
+    #[error("Maximum loss of {max_loss_bps} bps is above 10000 bps.")]
+    InvalidMaxLoss { max_loss_bps: u16 },
+
+    #[error("Price sources can only be set for assets in the asset list.")]
+    UnlistedPriceSource {},
+
+    #[error("Asset has no price source: {asset}")]
+    MissingPriceSource { asset: String },
+
+    #[error("The price source of {asset} still answers, change it with update_state.")]
+    PriceSourceAnswers { asset: String },
+
+    #[error("Cannot migrate from contract {contract}.")]
+    WrongContract { contract: String },
+
+    #[error("Invalid contract version: {version}")]
+    InvalidVersion { version: String },
+
+    #[error("Cannot migrate from version {stored} down to {new}.")]
+    CannotDowngrade { stored: String, new: String },
+
+    #[error("No approved migration proposal for code ID {code_id}.")]
+    MigrationNotApproved { code_id: u64 },
+
+    #[error("Migration proposal has expired.")]
+    MigrationProposalExpired {},
+
+    #[error("No migration proposal has been approved.")]
+    NoApprovedMigration {},
+
+    #[error("The wallet must be its own admin to migrate.")]
+    NotOwnAdmin {},
+
+    #[error("Not available in a pooled wallet, redeem shares instead.")]
+    Pooled {},
+
+    #[error("Only available in a pooled wallet.")]
+    NotPooled {},
+
+    #[error("Cannot redeem {shares} shares, only {held} are held.")]
+    InsufficientShares { shares: Uint128, held: Uint128 },
+
+    #[error("The wallet is worth nothing, so its shares can't be priced.")]
+    ZeroNav {},
+
+    #[error("Deposit is too small to buy a share.")]
+    ZeroShares {},
+
+    #[error("Shares are held as tokens, send them to the wallet to redeem.")]
+    RedeemShareToken {},
+
+    #[error("Unknown reply id: {id}")]
+    UnknownReplyId { id: u64 },
 }
@@ -1,9 +1,20 @@
 pub mod contract;
 mod error;
+mod migrations;
 pub mod msg;
+pub mod payload;
+pub mod split;
 pub mod state;
 pub mod util;
+pub mod valuation;
+pub mod whitelist;
 
 pub use crate::error::ContractError;
 
+// Default lower bound on commission, in percent
 pub const MINIMUM_COMMISSION: u8 = 20;
+
+// Number of blocks a migration proposal stays valid for (about a week)
+pub const MIGRATION_PROPOSAL_BLOCKS: u64 = 100_800;
+
+pub const SECONDS_PER_YEAR: u64 = 31_536_000;
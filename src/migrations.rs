@@ -0,0 +1,347 @@
+use cosmwasm_std::{Addr, Decimal, Empty, Storage, StdResult, Uint128};
+use cw_storage_plus::{Item, Map};
+use schemars::JsonSchema;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ContractError;
+use crate::state::Asset;
+use crate::valuation::{AssetPrice, PriceSource};
+use crate::MINIMUM_COMMISSION;
+
+type Migration = fn(&mut dyn Storage) -> StdResult<()>;
+
+// Every state layout change, in order, listed with the version that
+// introduced it. A migration runs when the stored version is older.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("0.2.0", v0_2_0::migrate),
+    ("0.4.0", v0_4_0::migrate),
+    ("0.5.0", v0_5_0::migrate),
+    ("0.6.0", v0_6_0::migrate),
+    ("0.7.0", v0_7_0::migrate),
+];
+
+// Run the migrations needed to bring storage from `from` up to date,
+// returning the versions that were migrated to
+pub fn run(storage: &mut dyn Storage, from: &Version) -> Result<Vec<String>, ContractError> {
+    let mut applied = vec![];
+    for (version, migration) in MIGRATIONS {
+        let target = parse_version(version)?;
+        if from < &target {
+            migration(storage)?;
+            applied.push(version.to_string());
+        }
+    }
+    Ok(applied)
+}
+
+pub fn parse_version(version: &str) -> Result<Version, ContractError> {
+    Version::parse(version).map_err(|_| ContractError::InvalidVersion { version: version.to_string() })
+}
+
+// Each migration keeps its own copy of the layouts it moves between, so
+// later changes to `State` don't change what it reads or writes.
+//
+// 0.2.0 added the base asset and per-asset price sources
+mod v0_2_0 {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct StateV0_1 {
+        trader: Addr,
+        trader_withdrawal_address: Addr,
+        funder: Addr,
+        assets: Vec<Asset>,
+        whitelist: Vec<Addr>,
+        trader_lock: bool,
+        funder_lock: bool,
+        base_investment: Uint128,
+        commission: u8,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct StateV0_2 {
+        trader: Addr,
+        trader_withdrawal_address: Addr,
+        funder: Addr,
+        base_asset: Asset,
+        assets: Vec<Asset>,
+        whitelist: Vec<Addr>,
+        trader_lock: bool,
+        funder_lock: bool,
+        base_investment: Uint128,
+        commission: u8,
+        price_sources: Vec<AssetPrice>,
+    }
+
+    const STATE_V0_1: Item<StateV0_1> = Item::new("state");
+    const STATE_V0_2: Item<StateV0_2> = Item::new("state");
+
+    pub fn migrate(storage: &mut dyn Storage) -> StdResult<()> {
+        let old = STATE_V0_1.load(storage)?;
+        let state = StateV0_2 {
+            trader: old.trader,
+            trader_withdrawal_address: old.trader_withdrawal_address,
+            funder: old.funder,
+            // Every instance before 0.2.0 was funded in Luna
+            base_asset: Asset::Native(String::from("uluna")),
+            assets: old.assets,
+            whitelist: old.whitelist,
+            trader_lock: old.trader_lock,
+            funder_lock: old.funder_lock,
+            base_investment: old.base_investment,
+            commission: old.commission,
+            price_sources: vec![],
+        };
+        STATE_V0_2.save(storage, &state)
+    }
+}
+
+// 0.4.0 replaced the carried forward loss with a high-water mark
+mod v0_4_0 {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct StateV0_3 {
+        trader: Addr,
+        trader_withdrawal_address: Addr,
+        funder: Addr,
+        base_asset: Asset,
+        assets: Vec<Asset>,
+        whitelist: Vec<Addr>,
+        trader_lock: bool,
+        funder_lock: bool,
+        base_investment: Uint128,
+        #[serde(default)]
+        loss_carryforward: Uint128,
+        commission: u8,
+        price_sources: Vec<AssetPrice>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct StateV0_4 {
+        trader: Addr,
+        trader_withdrawal_address: Addr,
+        funder: Addr,
+        base_asset: Asset,
+        assets: Vec<Asset>,
+        whitelist: Vec<Addr>,
+        trader_lock: bool,
+        funder_lock: bool,
+        base_investment: Uint128,
+        high_water_mark: Uint128,
+        commission: u8,
+        price_sources: Vec<AssetPrice>,
+    }
+
+    const STATE_V0_3: Item<StateV0_3> = Item::new("state");
+    const STATE_V0_4: Item<StateV0_4> = Item::new("state");
+
+    pub fn migrate(storage: &mut dyn Storage) -> StdResult<()> {
+        let old = STATE_V0_3.load(storage)?;
+        let state = StateV0_4 {
+            trader: old.trader,
+            trader_withdrawal_address: old.trader_withdrawal_address,
+            funder: old.funder,
+            base_asset: old.base_asset,
+            assets: old.assets,
+            whitelist: old.whitelist,
+            trader_lock: old.trader_lock,
+            funder_lock: old.funder_lock,
+            // Commission was owed on anything above the investment plus any loss still to make up
+            high_water_mark: old.base_investment + old.loss_carryforward,
+            base_investment: old.base_investment,
+            commission: old.commission,
+            price_sources: old.price_sources,
+        };
+        STATE_V0_4.save(storage, &state)
+    }
+}
+
+// 0.5.0 made commission a decimal, bounded per instance
+mod v0_5_0 {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct CommissionTierV0_4 {
+        threshold: Decimal,
+        commission: u8,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct CommissionTierV0_5 {
+        threshold: Decimal,
+        commission: Decimal,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct StateV0_4 {
+        trader: Addr,
+        trader_withdrawal_address: Addr,
+        funder: Addr,
+        base_asset: Asset,
+        assets: Vec<Asset>,
+        whitelist: Vec<Addr>,
+        trader_lock: bool,
+        funder_lock: bool,
+        base_investment: Uint128,
+        high_water_mark: Uint128,
+        #[serde(default)]
+        hurdle_rate: Option<Decimal>,
+        #[serde(default)]
+        last_crystallization: u64,
+        commission: u8,
+        #[serde(default)]
+        commission_tiers: Vec<CommissionTierV0_4>,
+        #[serde(default)]
+        management_fee: Option<Decimal>,
+        #[serde(default)]
+        accrued_fee: Uint128,
+        #[serde(default)]
+        last_fee_accrual: u64,
+        price_sources: Vec<AssetPrice>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct StateV0_5 {
+        trader: Addr,
+        trader_withdrawal_address: Addr,
+        funder: Addr,
+        base_asset: Asset,
+        assets: Vec<Asset>,
+        whitelist: Vec<Addr>,
+        trader_lock: bool,
+        funder_lock: bool,
+        base_investment: Uint128,
+        high_water_mark: Uint128,
+        hurdle_rate: Option<Decimal>,
+        last_crystallization: u64,
+        commission: Decimal,
+        min_commission: Decimal,
+        max_commission: Decimal,
+        commission_tiers: Vec<CommissionTierV0_5>,
+        management_fee: Option<Decimal>,
+        accrued_fee: Uint128,
+        last_fee_accrual: u64,
+        price_sources: Vec<AssetPrice>,
+    }
+
+    const STATE_V0_4: Item<StateV0_4> = Item::new("state");
+    const STATE_V0_5: Item<StateV0_5> = Item::new("state");
+
+    pub fn migrate(storage: &mut dyn Storage) -> StdResult<()> {
+        let old = STATE_V0_4.load(storage)?;
+        let state = StateV0_5 {
+            trader: old.trader,
+            trader_withdrawal_address: old.trader_withdrawal_address,
+            funder: old.funder,
+            base_asset: old.base_asset,
+            assets: old.assets,
+            whitelist: old.whitelist,
+            trader_lock: old.trader_lock,
+            funder_lock: old.funder_lock,
+            base_investment: old.base_investment,
+            high_water_mark: old.high_water_mark,
+            hurdle_rate: old.hurdle_rate,
+            last_crystallization: old.last_crystallization,
+            commission: Decimal::percent(old.commission.into()),
+            // The bounds that applied before they were configurable
+            min_commission: Decimal::percent(MINIMUM_COMMISSION.into()),
+            max_commission: Decimal::one(),
+            commission_tiers: old.commission_tiers
+                .into_iter()
+                .map(|tier| CommissionTierV0_5 {
+                    threshold: tier.threshold,
+                    commission: Decimal::percent(tier.commission.into()),
+                })
+                .collect(),
+            management_fee: old.management_fee,
+            accrued_fee: old.accrued_fee,
+            last_fee_accrual: old.last_fee_accrual,
+            price_sources: old.price_sources,
+        };
+        STATE_V0_5.save(storage, &state)
+    }
+}
+
+// 0.6.0 moved the whitelist out of the state into its own map
+mod v0_6_0 {
+    use super::*;
+
+    // Only the whitelist is read, the rest of the state is left as is. The
+    // stored list is ignored from now on, and dropped when the state is
+    // next saved.
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct WhitelistV0_5 {
+        whitelist: Vec<Addr>,
+    }
+
+    const STATE_V0_5: Item<WhitelistV0_5> = Item::new("state");
+    const WHITELIST_V0_6: Map<&Addr, Empty> = Map::new("whitelist");
+
+    pub fn migrate(storage: &mut dyn Storage) -> StdResult<()> {
+        let old = STATE_V0_5.load(storage)?;
+        for address in old.whitelist {
+            WHITELIST_V0_6.save(storage, &address, &Empty {})?;
+        }
+        Ok(())
+    }
+}
+
+// 0.7.0 stopped counting assets without a price source one to one with the
+// base asset, so those assets are given that price explicitly
+mod v0_7_0 {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct CommissionTierV0_6 {
+        threshold: Decimal,
+        commission: Decimal,
+    }
+
+    // The layout doesn't change, only the price sources
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    struct StateV0_6 {
+        trader: Addr,
+        trader_withdrawal_address: Addr,
+        funder: Addr,
+        #[serde(default)]
+        pooled: bool,
+        #[serde(default)]
+        total_shares: Uint128,
+        #[serde(default)]
+        share_token: Option<Addr>,
+        base_asset: Asset,
+        assets: Vec<Asset>,
+        trader_lock: bool,
+        funder_lock: bool,
+        base_investment: Uint128,
+        #[serde(default)]
+        realized_profit: Uint128,
+        #[serde(default)]
+        unrealized_profit: Uint128,
+        high_water_mark: Uint128,
+        hurdle_rate: Option<Decimal>,
+        last_crystallization: u64,
+        commission: Decimal,
+        min_commission: Decimal,
+        max_commission: Decimal,
+        commission_tiers: Vec<CommissionTierV0_6>,
+        management_fee: Option<Decimal>,
+        accrued_fee: Uint128,
+        last_fee_accrual: u64,
+        price_sources: Vec<AssetPrice>,
+    }
+
+    const STATE_V0_6: Item<StateV0_6> = Item::new("state");
+
+    pub fn migrate(storage: &mut dyn Storage) -> StdResult<()> {
+        let mut state = STATE_V0_6.load(storage)?;
+        for asset in state.assets.clone() {
+            if asset != state.base_asset && !state.price_sources.iter().any(|p| p.asset == asset) {
+                state.price_sources.push(AssetPrice { asset, source: PriceSource::Fixed(Decimal::one()) });
+            }
+        }
+        STATE_V0_6.save(storage, &state)
+    }
+}
@@ -1,8 +1,11 @@
-use cosmwasm_std::{Addr, Uint128, Binary, Coin};
+use cosmwasm_std::{Addr, Decimal, Uint128, Binary, Coin};
+use cw20::{Cw20Coin, Cw20ReceiveMsg, MinterResponse};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::state::{State, Asset};
+use crate::state::{State, Asset, AssetAmount, CommissionTier, MigrationProposal};
+use crate::split::WithdrawSplit;
+use crate::valuation::{AssetPrice, PriceSource};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ConfigItem {
@@ -13,33 +16,161 @@ pub struct ConfigItem {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct InstantiateMsg {
     pub funder: Addr,
+    pub base_asset: Asset,
     pub trader_withdrawal_address: Option<Addr>,
     pub whitelist: Option<Vec<Addr>>,
     pub assets: Option<Vec<Asset>>,
-    pub commission: Option<u8>,
+    pub commission: Option<Decimal>,
+    pub min_commission: Option<Decimal>,
+    pub max_commission: Option<Decimal>,
+    pub commission_tiers: Option<Vec<CommissionTier>>,
+    pub price_sources: Option<Vec<AssetPrice>>,
+    pub hurdle_rate: Option<Decimal>,
+    pub management_fee: Option<Decimal>,
+    // Let any number of funders deposit in exchange for shares
+    pub pooled: Option<bool>,
+    // Issue shares as a cw20 token, which makes the wallet pooled
+    pub share_token: Option<ShareTokenConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ShareTokenConfig {
+    // Code ID of a cw20-base compatible token
+    pub code_id: u64,
+    pub name: String,
+    pub symbol: String,
+}
+
+// Instantiate message for the share token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TokenInstantiateMsg {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub initial_balances: Vec<Cw20Coin>,
+    pub mint: Option<MinterResponse>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
     ToggleLock {},
-    UpdateState { whitelist: Option<Vec<Addr>>, assets: Option<Vec<Asset>>, commission: Option<u8>, user: Option<Addr> },
-    SendNative{ address: Addr, funds: Option<Vec<Coin>>, msg: Option<Binary> },
-    SendCw20 { address: Addr, token_addr: Addr, amount: Uint128, msg: Option<Binary> },
+    UpdateState { assets: Option<Vec<Asset>>, commission: Option<Decimal>, commission_tiers: Option<Vec<CommissionTier>>, user: Option<Addr>, price_sources: Option<Vec<AssetPrice>>, hurdle_rate: Option<Decimal>, management_fee: Option<Decimal> },
+    // Replace the price source of an asset whose source fails to answer,
+    // even while the wallet is locked
+    ReplacePriceSource { asset: Asset, source: PriceSource },
+    // Whitelist a contract, for only `allowed_msgs` if given
+    AddWhitelist { address: Addr, allowed_msgs: Option<Vec<String>> },
+    RemoveWhitelist { address: Addr },
+    // Whitelist every contract instantiated from `code_id`
+    AddCodeIdWhitelist { code_id: u64, allowed_msgs: Option<Vec<String>> },
+    RemoveCodeIdWhitelist { code_id: u64 },
+    // Whitelist every pair registered with `factory` that trades two of the assets
+    AddFactoryWhitelist { factory: Addr, allowed_msgs: Option<Vec<String>> },
+    RemoveFactoryWhitelist { factory: Addr },
+    SendNative{ address: Addr, funds: Option<Vec<Coin>>, msg: Option<Binary>, min_nav_after: Option<Uint128>, max_loss_bps: Option<u16> },
+    SendCw20 { address: Addr, token_addr: Addr, amount: Uint128, msg: Option<Binary>, min_nav_after: Option<Uint128>, max_loss_bps: Option<u16> },
     Deposit {},
-    Withdraw { amount: Option<Uint128> },
+    Withdraw { amount: Option<Uint128>, split: Option<WithdrawSplit>, preference: Option<Vec<Asset>>, min_out: Option<Vec<AssetAmount>> },
     UpdateWithdrawal { address: Addr },
+    Receive(Cw20ReceiveMsg),
+    ProposeMigration { code_id: u64 },
+    ApproveMigration { code_id: u64 },
+    // Have the wallet, as its own admin, migrate itself to the approved code ID
+    ExecuteMigration {},
+    ClaimManagementFee {},
+    ClaimCommission {},
+    Redeem { shares: Option<Uint128>, split: Option<WithdrawSplit> },
+}
+
+// Messages expected inside a cw20 `send` to this contract
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiveMsg {
+    Deposit {},
+    // Redeem the share tokens sent
+    Redeem { split: Option<WithdrawSplit> },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {
+    // Code ID being migrated to, which must match an approved proposal
+    pub code_id: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
-    // GetState returns the state data such as whitelisted addresses,
+    // GetState returns the state data such as assets,
     // profit_allocation, user/owner, etc.
     GetState {},
+    GetMigrationProposal {},
+    GetManagementFee {},
+    ListFunders { start_after: Option<Addr>, limit: Option<u32> },
+    ListWhitelist { start_after: Option<Addr>, limit: Option<u32> },
+    ListCodeIdWhitelist { start_after: Option<u64>, limit: Option<u32> },
+    ListFactoryWhitelist { start_after: Option<Addr>, limit: Option<u32> },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct StateResponse {
     pub state: State,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ManagementFeeResponse {
+    pub management_fee: Option<Decimal>,
+    // Fee owed to the trader as of the current block
+    pub accrued_fee: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrationProposalResponse {
+    pub proposal: Option<MigrationProposal>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FunderResponse {
+    pub address: Addr,
+    pub shares: Uint128,
+    pub cost_basis: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FundersResponse {
+    pub funders: Vec<FunderResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WhitelistEntryResponse {
+    pub address: Addr,
+    pub allowed_msgs: Option<Vec<String>>,
+    pub code_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WhitelistResponse {
+    pub entries: Vec<WhitelistEntryResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CodeIdRuleResponse {
+    pub code_id: u64,
+    pub allowed_msgs: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CodeIdWhitelistResponse {
+    pub rules: Vec<CodeIdRuleResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FactoryRuleResponse {
+    pub factory: Addr,
+    pub allowed_msgs: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FactoryWhitelistResponse {
+    pub rules: Vec<FactoryRuleResponse>,
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+
+use cosmwasm_std::{from_binary, Binary};
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+
+use crate::error::ContractError;
+
+// Fields naming who a message pays out to
+const RECIPIENT_KEYS: [&str; 3] = ["to", "recipient", "receiver"];
+// Fields holding a message for another contract, as base64 encoded JSON
+const NESTED_MSG_KEYS: [&str; 1] = ["msg"];
+// How many messages deep a forwarded message may nest others
+const MAX_NESTING: usize = 4;
+
+// A forwarded message parsed just enough to inspect it. Numbers, booleans
+// and nulls are skipped over, which keeps floats out of the contract.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+    Other,
+}
+
+impl<'de> Deserialize<'de> for Json {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Json, D::Error> {
+        // serde-json-wasm can't deserialize any value, but it can skip one,
+        // and the visitor sees what is skipped
+        deserializer.deserialize_ignored_any(JsonVisitor)
+    }
+}
+
+struct JsonVisitor;
+
+impl<'de> Visitor<'de> for JsonVisitor {
+    type Value = Json;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Json, E> {
+        Ok(Json::String(value.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Json, E> {
+        Ok(Json::String(value))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Json, E> {
+        Ok(Json::Other)
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<Json, E> {
+        Ok(Json::Other)
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<Json, E> {
+        Ok(Json::Other)
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<Json, E> {
+        Ok(Json::Other)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Json, A::Error> {
+        let mut items = vec![];
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Json::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Json, A::Error> {
+        let mut fields = vec![];
+        while let Some((Key(key), value)) = map.next_entry::<Key, Json>()? {
+            fields.push((key, value));
+        }
+        Ok(Json::Object(fields))
+    }
+}
+
+// An object key. serde-json-wasm reads keys as borrowed strings only.
+struct Key(String);
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error> {
+        deserializer.deserialize_str(KeyVisitor)
+    }
+}
+
+struct KeyVisitor;
+
+impl<'de> Visitor<'de> for KeyVisitor {
+    type Value = Key;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object key")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Key, E> {
+        Ok(Key(value.to_string()))
+    }
+}
+
+impl Json {
+    // The execute message a payload holds, which is the only key of the
+    // top-level object
+    pub fn variant(&self) -> Option<&str> {
+        match self {
+            Json::Object(fields) if fields.len() == 1 => Some(fields[0].0.as_str()),
+            _ => None,
+        }
+    }
+
+    // Check every recipient field, however deeply nested, is `wallet`.
+    // Messages nested for other contracts are decoded and checked in turn,
+    // and one that can't be decoded is rejected.
+    fn check_recipients(&self, wallet: &str, depth: usize) -> Result<(), ContractError> {
+        match self {
+            Json::Object(fields) => {
+                for (key, value) in fields {
+                    if RECIPIENT_KEYS.contains(&key.as_str()) {
+                        check_recipient(value, wallet)?;
+                    }
+                    match value {
+                        Json::String(encoded) if NESTED_MSG_KEYS.contains(&key.as_str()) => {
+                            if depth >= MAX_NESTING {
+                                return Err(ContractError::InvalidPayload {})
+                            }
+                            let nested = Binary::from_base64(encoded).map_err(|_| ContractError::InvalidPayload {})?;
+                            parse_payload(&nested)?.check_recipients(wallet, depth + 1)?;
+                        },
+                        _ => value.check_recipients(wallet, depth)?,
+                    }
+                }
+            },
+            Json::Array(items) => {
+                for item in items {
+                    item.check_recipients(wallet, depth)?;
+                }
+            },
+            Json::String(_) | Json::Other => {},
+        }
+        Ok(())
+    }
+}
+
+// A null recipient is left out, which pays the sender
+fn check_recipient(recipient: &Json, wallet: &str) -> Result<(), ContractError> {
+    match recipient {
+        Json::String(address) if address == wallet => Ok(()),
+        Json::Other => Ok(()),
+        Json::String(address) => Err(ContractError::ForeignRecipient { recipient: address.clone() }),
+        _ => Err(ContractError::InvalidPayload {}),
+    }
+}
+
+pub fn parse_payload(msg: &Binary) -> Result<Json, ContractError> {
+    from_binary(msg).map_err(|_| ContractError::InvalidPayload {})
+}
+
+// Check a message forwarded to `contract` is one of `allowed_msgs`. A
+// contract limited to some messages can't be sent a plain transfer.
+pub fn check_variant(
+    contract: &str,
+    msg: Option<&Binary>,
+    allowed_msgs: &Option<Vec<String>>,
+) -> Result<(), ContractError> {
+    let allowed_msgs = match allowed_msgs {
+        Some(allowed_msgs) => allowed_msgs,
+        None => return Ok(()),
+    };
+    let msg = msg.ok_or_else(|| ContractError::MessageRequired { contract: contract.to_string() })?;
+    let payload = parse_payload(msg)?;
+    let variant = payload.variant().ok_or(ContractError::InvalidPayload {})?;
+    if !allowed_msgs.iter().any(|allowed| allowed == variant) {
+        return Err(ContractError::MessageNotAllowed {
+            contract: contract.to_string(),
+            msg: variant.to_string(),
+        })
+    }
+    Ok(())
+}
+
+// Check every recipient in a forwarded message, and in the messages it
+// nests, is `wallet`
+pub fn check_recipients(msg: &Binary, wallet: &str) -> Result<(), ContractError> {
+    parse_payload(msg)?.check_recipients(wallet, 0)
+}
@@ -0,0 +1,110 @@
+use cosmwasm_std::Uint128;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::state::Asset;
+use crate::valuation::{total_value, AssetValue};
+
+// How a withdrawal is split across the assets held
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WithdrawSplit {
+    // The trader is paid first, then the funder, each taking assets in order
+    Waterfall,
+    // Each party gets the same share of every asset
+    ProRata,
+}
+
+// Written out because `#[default]` on a variant needs a newer compiler than
+// the optimizer builds with
+#[allow(clippy::derivable_impls)]
+impl Default for WithdrawSplit {
+    fn default() -> Self {
+        WithdrawSplit::Waterfall
+    }
+}
+
+// Assets owed to the trader and to the funder
+pub struct Payouts {
+    pub trader: Vec<(Asset, Uint128)>,
+    pub funder: Vec<(Asset, Uint128)>,
+}
+
+// Split `trader_funds` and `funder_withdrawal`, both valued in the base
+// denom, across the assets held. Each party's preference lists the assets
+// they would rather be paid in first.
+pub fn split_assets(
+    split: WithdrawSplit,
+    assets: Vec<AssetValue>,
+    trader_funds: Uint128,
+    funder_withdrawal: Uint128,
+    trader_preference: &[Asset],
+    funder_preference: &[Asset],
+) -> Payouts {
+    match split {
+        WithdrawSplit::Waterfall => {
+            let mut assets = assets;
+            Payouts {
+                trader: take_in_order(&mut assets, trader_preference, trader_funds),
+                funder: take_in_order(&mut assets, funder_preference, funder_withdrawal),
+            }
+        },
+        WithdrawSplit::ProRata => split_pro_rata(assets, trader_funds, funder_withdrawal),
+    }
+}
+
+// Take assets worth `value` out of `assets`, starting with those in
+// `preference` and then the rest in the order they are held
+fn take_in_order(
+    assets: &mut [AssetValue],
+    preference: &[Asset],
+    mut value: Uint128,
+) -> Vec<(Asset, Uint128)> {
+    let mut order: Vec<usize> = preference
+        .iter()
+        .filter_map(|asset| assets.iter().position(|held| &held.asset == asset))
+        .collect();
+    for index in 0..assets.len() {
+        if !order.contains(&index) {
+            order.push(index);
+        }
+    }
+
+    let mut taken = vec![];
+    for index in order {
+        if value.is_zero() {
+            break;
+        }
+        let asset = &mut assets[index];
+        let units = asset.units_for(value);
+        value = value.saturating_sub(asset.value_of(units));
+        asset.amount -= units;
+        taken.push((asset.asset.clone(), units));
+    }
+
+    taken
+}
+
+fn split_pro_rata(
+    assets: Vec<AssetValue>,
+    trader_funds: Uint128,
+    funder_withdrawal: Uint128,
+) -> Payouts {
+    let mut payouts = Payouts { trader: vec![], funder: vec![] };
+
+    let total = total_value(&assets);
+    if total.is_zero() {
+        return payouts;
+    }
+    let trader_funds = trader_funds.min(total);
+    let funder_withdrawal = funder_withdrawal.min(total - trader_funds);
+
+    for asset in assets {
+        let to_trader = asset.amount.multiply_ratio(trader_funds, total);
+        let to_funder = asset.amount.multiply_ratio(funder_withdrawal, total);
+        payouts.trader.push((asset.asset.clone(), to_trader));
+        payouts.funder.push((asset.asset, to_funder));
+    }
+
+    payouts
+}
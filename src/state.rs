@@ -1,9 +1,12 @@
+use std::fmt;
+
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{Addr, Uint128};
-use cw_storage_plus::Item;
-use crate::MINIMUM_COMMISSION;
+use cosmwasm_std::{Addr, Decimal, Uint128, Coin};
+use cw_storage_plus::{Item, Map, U64Key};
+use crate::{MINIMUM_COMMISSION, SECONDS_PER_YEAR};
+use crate::valuation::AssetPrice;
 
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -12,34 +15,231 @@ pub enum Asset {
     Token(Addr),
 }
 
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Asset::Native(denom) => write!(f, "{}", denom),
+            Asset::Token(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+// A commission rate paid on returns above `threshold`, a fraction of the
+// value commission is measured from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CommissionTier {
+    pub threshold: Decimal,
+    pub commission: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AssetAmount {
+    pub asset: Asset,
+    pub amount: Uint128,
+}
+
+// Instances created before the base asset was configurable hold Luna
+fn default_base_asset() -> Asset {
+    Asset::Native(String::from("uluna"))
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct State {
     pub trader: Addr,
     pub trader_withdrawal_address: Addr,
     pub funder: Addr,
+    // Whether any number of funders hold shares in the wallet, instead of
+    // `funder` alone
+    #[serde(default)]
+    pub pooled: bool,
+    // Shares issued to funders of a pooled wallet
+    #[serde(default)]
+    pub total_shares: Uint128,
+    // Token the shares are held as, once it has been instantiated
+    #[serde(default)]
+    pub share_token: Option<Addr>,
+    #[serde(default = "default_base_asset")]
+    pub base_asset: Asset,
     pub assets: Vec<Asset>,
-    pub whitelist: Vec<Addr>,
     pub trader_lock: bool,
     pub funder_lock: bool,
+    // Principal the funder has invested and not yet withdrawn
     pub base_investment: Uint128,
-    pub commission: u8,
+    // Profit the funder has withdrawn
+    #[serde(default)]
+    pub realized_profit: Uint128,
+    // Profit left invested, as of the last withdrawal or claim
+    #[serde(default)]
+    pub unrealized_profit: Uint128,
+    // Value the wallet must exceed before commission is paid
+    pub high_water_mark: Uint128,
+    // Annual return the wallet must beat before commission is paid
+    #[serde(default)]
+    pub hurdle_rate: Option<Decimal>,
+    // Block time, in seconds, commission was last paid at
+    #[serde(default)]
+    pub last_crystallization: u64,
+    pub commission: Decimal,
+    // Bounds every commission rate must stay within
+    pub min_commission: Decimal,
+    pub max_commission: Decimal,
+    // Higher commission rates for returns beyond each tier's threshold
+    #[serde(default)]
+    pub commission_tiers: Vec<CommissionTier>,
+    // Annual fee on assets under management, owed to the trader
+    #[serde(default)]
+    pub management_fee: Option<Decimal>,
+    // Management fee owed but not yet paid
+    #[serde(default)]
+    pub accrued_fee: Uint128,
+    // Block time, in seconds, the management fee was last accrued to
+    #[serde(default)]
+    pub last_fee_accrual: u64,
+    #[serde(default)]
+    pub price_sources: Vec<AssetPrice>,
 }
 
 impl State {
-    pub fn new(trader: Addr, funder: Addr) -> State {
+    pub fn new(trader: Addr, funder: Addr, base_asset: Asset, now: u64) -> State {
         let withdrawal_address = trader.clone();
         State {
             trader,
             trader_withdrawal_address: withdrawal_address,
             funder,
-            assets: vec![],
-            whitelist: vec![],
+            pooled: false,
+            total_shares: Uint128::new(0),
+            share_token: None,
+            base_asset: base_asset.clone(),
+            assets: vec![base_asset],
             trader_lock: false,
             funder_lock: false,
             base_investment: Uint128::new(0),
-            commission: MINIMUM_COMMISSION,
+            realized_profit: Uint128::new(0),
+            unrealized_profit: Uint128::new(0),
+            high_water_mark: Uint128::new(0),
+            hurdle_rate: None,
+            last_crystallization: now,
+            commission: Decimal::percent(MINIMUM_COMMISSION.into()),
+            min_commission: Decimal::percent(MINIMUM_COMMISSION.into()),
+            max_commission: Decimal::one(),
+            commission_tiers: vec![],
+            management_fee: None,
+            accrued_fee: Uint128::new(0),
+            last_fee_accrual: now,
+            price_sources: vec![],
+        }
+    }
+
+    // Commission owed on `profit` made above `basis`. Each part of the
+    // profit pays the rate of the band of return it falls in.
+    pub fn commission_on(&self, profit: Uint128, basis: Uint128) -> Uint128 {
+        let mut commission = Uint128::zero();
+        let mut rate = self.commission;
+        let mut band_start = Uint128::zero();
+        for tier in &self.commission_tiers {
+            let band_end = (basis * tier.threshold).min(profit);
+            if band_end > band_start {
+                commission += (band_end - band_start) * rate;
+                band_start = band_end;
+            }
+            rate = tier.commission;
         }
+        commission + (profit - band_start) * rate
     }
+
+    // Accrue the management fee, then crystallize commission on the value
+    // above the hurdle, returning the commission owed. Only value above
+    // the high-water mark counts as profit, so losses must be made up and
+    // past gains aren't paid for twice. A hurdle rate raises the mark by
+    // the return accrued since commission was last paid.
+    pub fn crystallize_commission(&mut self, total_balance: Uint128, now: u64) -> Uint128 {
+        self.accrue_management_fee(total_balance, now);
+        let nav = total_balance.saturating_sub(self.accrued_fee);
+
+        let mut hurdle = self.high_water_mark;
+        if let Some(rate) = self.hurdle_rate {
+            let elapsed = now.saturating_sub(self.last_crystallization);
+            hurdle += (self.high_water_mark * rate).multiply_ratio(elapsed, SECONDS_PER_YEAR);
+        }
+        let profit = nav.saturating_sub(hurdle);
+        let commission = self.commission_on(profit, hurdle);
+
+        // Crystallizing commission moves the mark up to what is left after it
+        if !profit.is_zero() {
+            self.high_water_mark = nav - commission;
+            self.last_crystallization = now;
+        }
+
+        commission
+    }
+
+    // Accrue the management fee on `nav` for the time since it last accrued
+    pub fn accrue_management_fee(&mut self, nav: Uint128, now: u64) {
+        if let Some(rate) = self.management_fee {
+            let elapsed = now.saturating_sub(self.last_fee_accrual);
+            let managed = nav.saturating_sub(self.accrued_fee);
+            self.accrued_fee += (managed * rate).multiply_ratio(elapsed, SECONDS_PER_YEAR);
+        }
+        self.last_fee_accrual = now;
+    }
+}
+
+// The top-level execute messages a whitelisted contract may be sent. Any
+// message is allowed when there is no list.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct WhitelistEntry {
+    #[serde(default)]
+    pub allowed_msgs: Option<Vec<String>>,
+    // Code ID the contract ran when it was whitelisted, unset for
+    // addresses that aren't contracts
+    #[serde(default)]
+    pub code_id: Option<u64>,
+}
+
+// Whitelists every contract instantiated from a code ID, or every pair
+// registered with a factory
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct WhitelistRule {
+    pub allowed_msgs: Option<Vec<String>>,
+}
+
+// A funder's stake in a pooled wallet. Commission is owed on what the
+// shares are worth above `cost_basis`, the value paid for them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct FunderPosition {
+    pub shares: Uint128,
+    pub cost_basis: Uint128,
+}
+
+// Limits on how much a single trade may lower the wallet's combined balance
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TradeGuard {
+    pub min_nav_after: Option<Uint128>,
+    pub max_loss_bps: Option<u16>,
+}
+
+// Balances held right before a trade is forwarded, compared against
+// the balances seen in the trade's reply.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TradeSnapshot {
+    pub assets: Vec<(Asset, Uint128)>,
+    pub native: Vec<Coin>,
+    pub guard: TradeGuard,
+}
+
+// A code ID the trader wants to migrate to, which the funder must approve
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrationProposal {
+    pub code_id: u64,
+    pub expires_at: u64,
+    pub approved: bool,
 }
 
 pub const STATE: Item<State> = Item::new("state");
+pub const TRADE_SNAPSHOT: Item<TradeSnapshot> = Item::new("trade_snapshot");
+pub const MIGRATION_PROPOSAL: Item<MigrationProposal> = Item::new("migration_proposal");
+// Addresses the trader may forward funds and messages to
+pub const WHITELIST: Map<&Addr, WhitelistEntry> = Map::new("whitelist");
+pub const CODE_ID_WHITELIST: Map<U64Key, WhitelistRule> = Map::new("code_id_whitelist");
+pub const FACTORY_WHITELIST: Map<&Addr, WhitelistRule> = Map::new("factory_whitelist");
+pub const FUNDERS: Map<&Addr, FunderPosition> = Map::new("funders");
@@ -1,6 +1,8 @@
-use cosmwasm_std::{QuerierWrapper, Addr, StdResult, Uint128, QueryRequest, WasmQuery, to_binary, CosmosMsg, WasmMsg, Response, Binary};
+use cosmwasm_std::{QuerierWrapper, Addr, StdResult, Uint128, QueryRequest, WasmQuery, to_binary, CosmosMsg, WasmMsg, Response, Binary, BankMsg, Coin};
 use cw20::{Cw20QueryMsg, BalanceResponse, Cw20ExecuteMsg};
 
+use crate::state::Asset;
+
 pub fn query_token_balance(
     querier: &QuerierWrapper,
     contract_addr: Addr,
@@ -51,3 +53,52 @@ where
         }
     }
 }
+
+// Send each of `assets` to `recipient`, natives in a single bank message
+pub fn add_asset_msgs<T: ToString>(
+    mut res: Response,
+    recipient: T,
+    assets: Vec<(Asset, Uint128)>,
+) -> Response {
+    let mut coins = vec![];
+    for (asset, amount) in assets {
+        if amount.is_zero() {
+            continue;
+        }
+        match asset {
+            Asset::Native(denom) => coins.push(Coin { denom, amount }),
+            Asset::Token(addr) => {
+                res = add_cw20_msg(res, addr, recipient.to_string(), amount, None);
+            },
+        }
+    }
+    if !coins.is_empty() {
+        res = res.add_message(CosmosMsg::Bank(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount: coins,
+        }));
+    }
+    res
+}
+
+pub fn query_asset_balance(
+    querier: &QuerierWrapper,
+    asset: &Asset,
+    account_addr: Addr,
+) -> StdResult<Uint128> {
+    match asset {
+        Asset::Native(denom) => Ok(querier.query_balance(account_addr, denom)?.amount),
+        Asset::Token(address) => query_token_balance(querier, address.clone(), account_addr),
+    }
+}
+
+pub fn query_asset_balances(
+    querier: &QuerierWrapper,
+    assets: &[Asset],
+    account_addr: Addr,
+) -> StdResult<Vec<(Asset, Uint128)>> {
+    assets
+        .iter()
+        .map(|asset| Ok((asset.clone(), query_asset_balance(querier, asset, account_addr.clone())?)))
+        .collect()
+}
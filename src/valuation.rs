@@ -0,0 +1,175 @@
+use cosmwasm_std::{Addr, Binary, Decimal, Fraction, QuerierWrapper, QueryRequest, StdError, StdResult, Uint128, WasmQuery, to_binary};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ContractError;
+use crate::state::{Asset, State};
+use crate::util::query_asset_balances;
+
+// Where the price of an asset, in units of the base denom, comes from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSource {
+    // A set number of base units per asset unit
+    Fixed(Decimal),
+    // A contract answering `query` with a `PriceResponse`
+    Oracle { contract: Addr, query: Binary },
+    // A liquid staking hub's redemption rate. `query` defaults to `state {}`
+    // and `rate_field` to the `exchange_rate` of its response.
+    Hub { contract: Addr, query: Option<Binary>, rate_field: Option<HubRateField> },
+}
+
+// Field of a hub's response holding the exchange rate
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HubRateField {
+    ExchangeRate,
+    BlunaExchangeRate,
+    StlunaExchangeRate,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AssetPrice {
+    pub asset: Asset,
+    pub source: PriceSource,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PriceResponse {
+    pub price: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum HubQueryMsg {
+    State {},
+}
+
+// The rate fields reported by the hubs we support, any other field is ignored
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+struct HubStateResponse {
+    exchange_rate: Option<Decimal>,
+    bluna_exchange_rate: Option<Decimal>,
+    stluna_exchange_rate: Option<Decimal>,
+}
+
+// A balance of an asset together with its price in the base denom
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AssetValue {
+    pub asset: Asset,
+    pub amount: Uint128,
+    pub price: Decimal,
+}
+
+impl AssetValue {
+    pub fn value(&self) -> Uint128 {
+        self.value_of(self.amount)
+    }
+
+    // Base denom value of `amount` units of the asset
+    pub fn value_of(&self, amount: Uint128) -> Uint128 {
+        amount * self.price
+    }
+
+    // Units of the asset worth `value` in the base denom, capped at the balance held
+    pub fn units_for(&self, value: Uint128) -> Uint128 {
+        if self.price.is_zero() {
+            return Uint128::zero();
+        }
+        value
+            .multiply_ratio(self.price.denominator(), self.price.numerator())
+            .min(self.amount)
+    }
+}
+
+pub fn query_price(
+    querier: &QuerierWrapper,
+    source: &PriceSource,
+) -> StdResult<Decimal> {
+    match source {
+        PriceSource::Fixed(price) => Ok(*price),
+        PriceSource::Oracle { contract, query } => {
+            let res: PriceResponse = querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                contract_addr: contract.to_string(),
+                msg: query.clone(),
+            }))?;
+            Ok(res.price)
+        },
+        PriceSource::Hub { contract, query, rate_field } => {
+            let msg = match query {
+                Some(query) => query.clone(),
+                None => to_binary(&HubQueryMsg::State {})?,
+            };
+            let res: HubStateResponse = querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                contract_addr: contract.to_string(),
+                msg,
+            }))?;
+            let rate = match rate_field.clone().unwrap_or(HubRateField::ExchangeRate) {
+                HubRateField::ExchangeRate => res.exchange_rate,
+                HubRateField::BlunaExchangeRate => res.bluna_exchange_rate,
+                HubRateField::StlunaExchangeRate => res.stluna_exchange_rate,
+            };
+            rate.ok_or_else(|| StdError::generic_err(format!("Hub {} did not return the requested exchange rate", contract)))
+        },
+    }
+}
+
+// Price of an asset in the base denom. Every other asset must have a
+// price source, a one to one peg is set with `Fixed(1)`.
+pub fn asset_price(
+    querier: &QuerierWrapper,
+    state: &State,
+    asset: &Asset,
+) -> StdResult<Decimal> {
+    if asset == &state.base_asset {
+        return Ok(Decimal::one());
+    }
+    match state.price_sources.iter().find(|p| &p.asset == asset) {
+        Some(price) => query_price(querier, &price.source),
+        None => Err(StdError::generic_err(format!("No price source for {}", asset))),
+    }
+}
+
+pub fn value_balances(
+    querier: &QuerierWrapper,
+    state: &State,
+    balances: Vec<(Asset, Uint128)>,
+) -> StdResult<Vec<AssetValue>> {
+    balances
+        .into_iter()
+        .map(|(asset, amount)| {
+            let price = asset_price(querier, state, &asset)?;
+            Ok(AssetValue { asset, amount, price })
+        })
+        .collect()
+}
+
+// Balances of every asset held by `account`, with their prices
+pub fn query_asset_values(
+    querier: &QuerierWrapper,
+    state: &State,
+    account: Addr,
+) -> StdResult<Vec<AssetValue>> {
+    let balances = query_asset_balances(querier, &state.assets, account)?;
+    value_balances(querier, state, balances)
+}
+
+pub fn total_value(values: &[AssetValue]) -> Uint128 {
+    values.iter().map(|v| v.value()).sum()
+}
+
+// Check every price source is for a listed asset, and every listed asset
+// other than the base asset has one
+pub fn validate_price_sources(state: &State) -> Result<(), ContractError> {
+    for price in &state.price_sources {
+        if !state.assets.contains(&price.asset) {
+            return Err(ContractError::UnlistedPriceSource {})
+        }
+    }
+    for asset in &state.assets {
+        if asset != &state.base_asset && !state.price_sources.iter().any(|p| &p.asset == asset) {
+            return Err(ContractError::MissingPriceSource { asset: asset.to_string() })
+        }
+    }
+    Ok(())
+}
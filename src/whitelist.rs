@@ -0,0 +1,149 @@
+use cosmwasm_std::{from_binary, to_binary, to_vec, Addr, ContractResult, Deps, Order, QuerierWrapper, QueryRequest, StdError, StdResult, Storage, SystemError, SystemResult, WasmQuery};
+use cw_storage_plus::U64Key;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use terra_cosmwasm::{ContractInfoResponse, TerraQuery, TerraQueryWrapper, TerraRoute};
+
+use crate::error::ContractError;
+use crate::state::{Asset, State, WhitelistEntry, CODE_ID_WHITELIST, FACTORY_WHITELIST, WHITELIST};
+
+// An asset as Terraswap-style pairs and factories describe it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetInfo {
+    Token { contract_addr: String },
+    NativeToken { denom: String },
+}
+
+impl From<AssetInfo> for Asset {
+    fn from(info: AssetInfo) -> Asset {
+        match info {
+            AssetInfo::Token { contract_addr } => Asset::Token(Addr::unchecked(contract_addr)),
+            AssetInfo::NativeToken { denom } => Asset::Native(denom),
+        }
+    }
+}
+
+// A pair's own `pair {}` response, and the factory's for the pair it registered
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PairInfo {
+    pub asset_infos: [AssetInfo; 2],
+    pub contract_addr: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PairQueryMsg {
+    Pair {},
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FactoryQueryMsg {
+    Pair { asset_infos: [AssetInfo; 2] },
+}
+
+// Code ID a contract was instantiated from, or none if there is no
+// contract at `address`. Any other failure is returned, so a contract is
+// never mistaken for an account.
+pub fn query_code_id(querier: &QuerierWrapper, address: &Addr) -> StdResult<Option<u64>> {
+    let request: QueryRequest<TerraQueryWrapper> = TerraQueryWrapper {
+        route: TerraRoute::Wasm,
+        query_data: TerraQuery::ContractInfo { contract_address: address.to_string() },
+    }.into();
+    match querier.raw_query(&to_vec(&request)?) {
+        SystemResult::Err(SystemError::NoSuchContract { .. }) => Ok(None),
+        SystemResult::Err(err) => Err(StdError::generic_err(format!("Querier system error: {}", err))),
+        SystemResult::Ok(ContractResult::Err(err)) => Err(StdError::generic_err(format!("Querier contract error: {}", err))),
+        SystemResult::Ok(ContractResult::Ok(value)) => {
+            let info: ContractInfoResponse = from_binary(&value)?;
+            Ok(Some(info.code_id))
+        },
+    }
+}
+
+fn query_pair<T: Serialize>(querier: &QuerierWrapper, contract: &str, msg: &T) -> StdResult<PairInfo> {
+    querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+        contract_addr: contract.to_string(),
+        msg: to_binary(msg)?,
+    }))
+}
+
+// The messages the trader may forward to `address`, or none to allow any.
+// An address whitelisted on its own must still run the code it was
+// approved with, and any other contract must run a whitelisted code ID or
+// be a pair registered with a whitelisted factory.
+pub fn allowed_msgs(deps: Deps, state: &State, address: &Addr) -> Result<Option<Vec<String>>, ContractError> {
+    if let Some(entry) = WHITELIST.may_load(deps.storage, address)? {
+        if let Some(code_id) = entry.code_id {
+            let current = query_code_id(&deps.querier, address)?.ok_or(ContractError::NotWhitelisted {})?;
+            if current != code_id {
+                return Err(ContractError::CodeIdChanged {
+                    contract: address.to_string(),
+                    code_id,
+                    current,
+                })
+            }
+        }
+        return Ok(entry.allowed_msgs)
+    }
+
+    if let Some(code_id) = query_code_id(&deps.querier, address)? {
+        if let Some(rule) = CODE_ID_WHITELIST.may_load(deps.storage, U64Key::new(code_id))? {
+            return Ok(rule.allowed_msgs)
+        }
+    }
+
+    factory_pair_allowed_msgs(deps, state, address)
+}
+
+// Check `address` is a pair a whitelisted factory registered for its
+// assets, and that it only trades between the wallet's assets
+fn factory_pair_allowed_msgs(deps: Deps, state: &State, address: &Addr) -> Result<Option<Vec<String>>, ContractError> {
+    let factories = FACTORY_WHITELIST
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    if factories.is_empty() {
+        return Err(ContractError::NotWhitelisted {})
+    }
+
+    let pair = query_pair(&deps.querier, address.as_str(), &PairQueryMsg::Pair {})
+        .map_err(|_| ContractError::NotWhitelisted {})?;
+    for (factory, rule) in factories {
+        let factory = String::from_utf8(factory).map_err(|_| ContractError::NotWhitelisted {})?;
+        let msg = FactoryQueryMsg::Pair { asset_infos: pair.asset_infos.clone() };
+        let registered = match query_pair(&deps.querier, &factory, &msg) {
+            Ok(registered) => registered,
+            Err(_) => continue,
+        };
+        if registered.contract_addr != address.as_str() {
+            continue;
+        }
+
+        for info in pair.asset_infos {
+            let asset = Asset::from(info);
+            if !state.assets.contains(&asset) {
+                return Err(ContractError::UnlistedAsset { asset: asset.to_string() })
+            }
+        }
+        return Ok(rule.allowed_msgs)
+    }
+
+    Err(ContractError::NotWhitelisted {})
+}
+
+// Pin every contract whitelisted without a code ID to the code it runs now,
+// such as those carried over from before entries were pinned
+pub fn pin_whitelist(storage: &mut dyn Storage, querier: &QuerierWrapper) -> StdResult<()> {
+    let unpinned = WHITELIST
+        .range(storage, None, None, Order::Ascending)
+        .filter(|item| !matches!(item, Ok((_, entry)) if entry.code_id.is_some()))
+        .collect::<StdResult<Vec<_>>>()?;
+    for (address, entry) in unpinned {
+        let address = Addr::unchecked(String::from_utf8(address)?);
+        if let Some(code_id) = query_code_id(querier, &address)? {
+            WHITELIST.save(storage, &address, &WhitelistEntry { code_id: Some(code_id), ..entry })?;
+        }
+    }
+    Ok(())
+}